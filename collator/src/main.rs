@@ -3,7 +3,7 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use powerplay::{HeadData as PowerplayHead, BlockData as PowerplayBody, CrossChain}; 
+use powerplay::{HeadData as PowerplayHead, BlockData as PowerplayBody, CrossChain, State};
 use sp_core::Pair;
 use codec::{Encode, Decode};
 use primitives::{
@@ -16,20 +16,38 @@ use collator::{
 use parking_lot::Mutex;
 use futures::future::{Ready, ok, err, TryFutureExt};
 
-const GENESIS: PowerplayHead = PowerplayHead {
-	number: 0,
-	parent_hash: [0; 32],
-};
+/// Environment variable to set the para id with.
+const PARA_ID_VAR: &str = "POWERPLAY_PARA_ID";
 
-const GENESIS_BODY: PowerplayBody = PowerplayBody, CrossChain {
-	state: 0,
-	add: 0,
-	data: crosschain,
-};
+/// The para id used when none is set.
+const DEFAULT_PARA_ID: u32 = 100;
+
+/// The para id to collate for, from `PARA_ID_VAR` or `DEFAULT_PARA_ID`.
+fn para_id() -> Result<ParaId, Box<dyn std::error::Error>> {
+	match std::env::var(PARA_ID_VAR) {
+		Ok(id) => Ok(id.parse::<u32>()
+			.map_err(|e| format!("Invalid {}: {}", PARA_ID_VAR, e))?
+			.into()),
+		Err(std::env::VarError::NotPresent) => Ok(DEFAULT_PARA_ID.into()),
+		Err(e) => Err(format!("Invalid {}: {}", PARA_ID_VAR, e).into()),
+	}
+}
+
+/// The genesis head and state of the parachain `id`.
+fn genesis(id: ParaId) -> (PowerplayHead, State) {
+	let state = State::new(u32::from(id).into());
+	let head = PowerplayHead {
+		number: 0,
+		parent_hash: [0; 32],
+		post_state: state.hash(),
+	};
+	(head, state)
+}
 
 #[derive(Clone)]
 struct PowerplayContext {
-	db: Arc<Mutex<HashMap<PowerplayHead, PowerplayBody>>>,
+	/// The post-state of every head produced so far.
+	db: Arc<Mutex<HashMap<PowerplayHead, State>>>,
 	/// We store it here to make sure that our interfaces require the correct bounds.
 	_network: Option<Arc<dyn Network>>,
 }
@@ -52,31 +70,25 @@ impl ParachainContext for PowerplayContext {
 
 		let mut db = self.db.lock();
 
-		let last_body = if powerplay_head == GENESIS {
-			GENESIS_BODY
-		} else {
-			db.get(&powerplay_head)
-				.expect("All past bodies stored since this is the only collator")
-				.clone()
-		};
+		let state = db.get(&powerplay_head)
+			.expect("All past states stored since this is the only collator")
+			.clone();
 
-		let next_body = PowerplayBody, CrossChain {
-			state: last_body.state.overflowing_add(last_body.add).0,
-			add: powerplay_head.number % 100,
-			data: transfer_money(&mut self, account_id: String, amount: u64)
+		let next_body = PowerplayBody {
+			state,
+			data: CrossChain::default(),
+			transactions: Vec::new(),
 		};
 
-		let next_head = powerplay::execute(powerplay_head.hash(), powerplay_head, &next_body)
+		let (next_head, post_state) = powerplay::apply(powerplay_head.hash(), powerplay_head, &next_body)
 			.expect("good execution params; qed");
 
 		let encoded_head = HeadData(next_head.encode());
 		let encoded_body = BlockData(next_body.encode());
-		let encoded_body = CrossChain(next.body.encode());
 
-		println!("Created collation for #{}, post-state={}",
-			next_head.number, next_body.state.overflowing_add(next_body.add).0);
+		println!("Created collation for #{}, post-state={:?}", next_head.number, next_head.post_state);
 
-		db.insert(next_head.clone(), next_body);
+		db.insert(next_head, post_state);
 		ok((encoded_body, encoded_head))
 	}
 }
@@ -96,12 +108,14 @@ impl BuildParachainContext for PowerplayContext {
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 	let key = Arc::new(Pair::from_seed(&[1; 32]));
-	let id: ParaId = 100.into();
+	let id = para_id()?;
 
-	println!("Starting powerplay collator with genesis: ");
+	let (genesis_head, genesis_state) = genesis(id);
+
+	println!("Starting powerplay collator for para id {} with genesis: ", u32::from(id));
 
 	{
-		let encoded = GENESIS.encode();
+		let encoded = genesis_head.encode();
 		println!("Dec: {:?}", encoded);
 		print!("Hex: 0x");
 		for byte in encoded {
@@ -111,8 +125,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 		println!();
 	}
 
+	println!("Contract metadata: {}", CrossChain::abi().to_json());
+
+	let mut db = HashMap::new();
+	db.insert(genesis_head, genesis_state);
+
 	let context = PowerplayContext {
-		db: Arc::new(Mutex::new(HashMap::new())),
+		db: Arc::new(Mutex::new(db)),
 		_network: None,
 	};
 
@@ -0,0 +1,167 @@
+//! Procedural macros for powerplay contracts.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+	parse_macro_input, spanned::Spanned, Attribute, FnArg, GenericArgument, ImplItem, ItemImpl,
+	Pat, PathArguments, ReturnType, Type, Visibility,
+};
+
+/// Generate metadata for the methods of an `impl` block.
+///
+/// Adds an associated function `abi() -> powerplay::abi::Abi` to the type, describing every
+/// public method taking `self`. A method is a call if it takes `&mut self`, takes callback
+/// arguments, returns a `Promise` or `PromiseOrValue`, or is annotated with `#[call]`. All other
+/// methods are views.
+///
+/// The following helper attributes are understood and removed:
+///
+/// - `#[call]` on a method, to mark it as a call.
+/// - `#[result_encode]` on a method, stating that the result is SCALE encoded, which is always
+///   the case.
+/// - `#[callback]` on an argument, which is then taken from a promise result instead of the
+///   call arguments.
+/// - `#[encode]` on an argument, stating that it is SCALE encoded, which is always the case.
+#[proc_macro_attribute]
+pub fn abi(attr: TokenStream, item: TokenStream) -> TokenStream {
+	if !attr.is_empty() {
+		let attr = TokenStream2::from(attr);
+		return syn::Error::new(attr.span(), "`abi` takes no arguments").to_compile_error().into();
+	}
+
+	let mut item = parse_macro_input!(item as ItemImpl);
+	match expand(&mut item) {
+		Ok(abi) => quote!(#item #abi).into(),
+		Err(err) => err.to_compile_error().into(),
+	}
+}
+
+fn expand(item: &mut ItemImpl) -> syn::Result<TokenStream2> {
+	let mut methods = Vec::new();
+
+	for impl_item in item.items.iter_mut() {
+		let method = match impl_item {
+			ImplItem::Method(method) => method,
+			_ => continue,
+		};
+
+		let marked_call = take_marker(&mut method.attrs, "call");
+		take_marker(&mut method.attrs, "result_encode");
+
+		let mut receiver = None;
+		let mut args = Vec::new();
+		let mut has_callback = false;
+		for input in method.sig.inputs.iter_mut() {
+			let input = match input {
+				FnArg::Receiver(r) => {
+					receiver = Some(r.reference.is_none() || r.mutability.is_some());
+					continue;
+				},
+				FnArg::Typed(input) => input,
+			};
+
+			let callback = take_marker(&mut input.attrs, "callback");
+			take_marker(&mut input.attrs, "encode");
+			has_callback |= callback;
+
+			let name = match &*input.pat {
+				Pat::Ident(pat) => pat.ident.to_string(),
+				pat => return Err(syn::Error::new(pat.span(), "expected a plain argument name")),
+			};
+			let ty = &input.ty;
+			args.push(quote! {
+				powerplay::abi::Arg {
+					name: #name,
+					layout: <#ty as powerplay::abi::TypeLayout>::layout(),
+					callback: #callback,
+				}
+			});
+		}
+
+		let mutates = match receiver {
+			Some(mutates) => mutates,
+			// Associated functions can't be called on a contract.
+			None => continue,
+		};
+		if let Visibility::Public(_) = method.vis {} else {
+			continue;
+		}
+
+		let (returns, returns_promise) = return_layout(&method.sig.output);
+		let kind = if mutates || has_callback || returns_promise || marked_call {
+			quote!(powerplay::abi::MethodKind::Call)
+		} else {
+			quote!(powerplay::abi::MethodKind::View)
+		};
+
+		let name = method.sig.ident.to_string();
+		methods.push(quote! {
+			powerplay::abi::Method {
+				name: #name,
+				kind: #kind,
+				args: powerplay::abi::vec![#( #args ),*],
+				returns: #returns,
+			}
+		});
+	}
+
+	let self_ty = &item.self_ty;
+	let type_name = quote!(#self_ty).to_string();
+	let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+
+	Ok(quote! {
+		impl #impl_generics #self_ty #where_clause {
+			/// Metadata describing the callable methods of this type.
+			pub fn abi() -> powerplay::abi::Abi {
+				powerplay::abi::Abi {
+					name: #type_name,
+					methods: powerplay::abi::vec![#( #methods ),*],
+				}
+			}
+		}
+	})
+}
+
+/// Remove the attribute `#[name]` from `attrs`, returning whether it was present.
+fn take_marker(attrs: &mut Vec<Attribute>, name: &str) -> bool {
+	let len = attrs.len();
+	attrs.retain(|attr| !attr.path.is_ident(name));
+	attrs.len() != len
+}
+
+/// The layout of a method's return value, and whether the method returns a promise.
+fn return_layout(output: &ReturnType) -> (TokenStream2, bool) {
+	let ty = match output {
+		ReturnType::Default => return (quote!(None), false),
+		ReturnType::Type(_, ty) => &**ty,
+	};
+
+	if let Type::Tuple(tuple) = ty {
+		if tuple.elems.is_empty() {
+			return (quote!(None), false);
+		}
+	}
+
+	if let Type::Path(path) = ty {
+		let last = path.path.segments.last().expect("paths have at least one segment; qed");
+		if last.ident == "Promise" {
+			return (quote!(Some(powerplay::abi::Layout::Promise)), true);
+		}
+
+		if last.ident == "PromiseOrValue" {
+			if let PathArguments::AngleBracketed(args) = &last.arguments {
+				if let Some(GenericArgument::Type(value)) = args.args.first() {
+					return (
+						quote!(Some(<#value as powerplay::abi::TypeLayout>::layout())),
+						true,
+					);
+				}
+			}
+		}
+	}
+
+	(quote!(Some(<#ty as powerplay::abi::TypeLayout>::layout())), false)
+}
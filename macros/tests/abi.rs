@@ -0,0 +1,144 @@
+//! Metadata generated for a contract by the `abi` attribute.
+
+#![allow(dead_code, unused_variables)]
+
+use powerplay::abi::{self, Abi, Arg, Layout, Method, MethodKind};
+use serde_json::json;
+
+struct Promise;
+
+enum PromiseOrValue<T> {
+	Value(T),
+	Promise(Promise),
+}
+
+struct StatusMessage;
+
+#[abi::abi]
+impl StatusMessage {
+	pub fn new() -> Self {
+		StatusMessage
+	}
+
+	pub fn get_status(&self, account_id: [u8; 32]) -> Option<String> {
+		None
+	}
+
+	pub fn set_status(&mut self, message: String) {}
+
+	#[call]
+	pub fn deploy(&self, amount: u64) {}
+
+	#[result_encode]
+	pub fn build_sort(&self, arr: Vec<u8>) -> PromiseOrValue<Vec<u8>> {
+		PromiseOrValue::Value(arr)
+	}
+
+	#[result_encode]
+	pub fn build(
+		&self,
+		#[callback]
+		#[encode]
+		data0: Vec<u8>,
+		#[callback]
+		#[encode]
+		data1: Vec<u8>,
+	) -> Vec<u8> {
+		data0
+	}
+
+	pub fn forward(&self, account_id: [u8; 32], messages: Vec<(u32, String)>) -> Promise {
+		Promise
+	}
+
+	fn internal(&self) {}
+}
+
+fn arg(name: &'static str, layout: Layout) -> Arg {
+	Arg { name, layout, callback: false }
+}
+
+fn bytes() -> Layout {
+	Layout::Sequence(Box::new(Layout::U8))
+}
+
+#[test]
+fn methods_are_described() {
+	let account_id = Layout::Array { len: 32, element: Box::new(Layout::U8) };
+	let callback = |name| Arg { name, layout: bytes(), callback: true };
+
+	assert_eq!(StatusMessage::abi(), Abi {
+		name: "StatusMessage",
+		methods: vec![
+			Method {
+				name: "get_status",
+				kind: MethodKind::View,
+				args: vec![arg("account_id", account_id.clone())],
+				returns: Some(Layout::Option(Box::new(Layout::Str))),
+			},
+			Method {
+				name: "set_status",
+				kind: MethodKind::Call,
+				args: vec![arg("message", Layout::Str)],
+				returns: None,
+			},
+			Method {
+				name: "deploy",
+				kind: MethodKind::Call,
+				args: vec![arg("amount", Layout::U64)],
+				returns: None,
+			},
+			Method {
+				name: "build_sort",
+				kind: MethodKind::Call,
+				args: vec![arg("arr", bytes())],
+				returns: Some(bytes()),
+			},
+			Method {
+				name: "build",
+				kind: MethodKind::Call,
+				args: vec![callback("data0"), callback("data1")],
+				returns: Some(bytes()),
+			},
+			Method {
+				name: "forward",
+				kind: MethodKind::Call,
+				args: vec![
+					arg("account_id", account_id),
+					arg("messages", Layout::Sequence(Box::new(
+						Layout::Tuple(vec![Layout::U32, Layout::Str]),
+					))),
+				],
+				returns: Some(Layout::Promise),
+			},
+		],
+	});
+}
+
+#[test]
+fn metadata_is_serialized_to_json() {
+	let abi = StatusMessage::abi();
+	let json: serde_json::Value = serde_json::from_str(&abi.to_json()).unwrap();
+
+	assert_eq!(json["name"], "StatusMessage");
+	assert_eq!(json["methods"].as_array().unwrap().len(), abi.methods.len());
+	assert_eq!(json["methods"][0], json!({
+		"name": "get_status",
+		"kind": "view",
+		"args": [{
+			"name": "account_id",
+			"layout": { "array": { "len": 32, "element": "u8" } },
+			"callback": false,
+		}],
+		"returns": { "option": "str" },
+	}));
+	assert_eq!(json["methods"][4]["args"][0], json!({
+		"name": "data0",
+		"layout": { "sequence": "u8" },
+		"callback": true,
+	}));
+	assert_eq!(json["methods"][5]["returns"], "promise");
+	assert_eq!(json["methods"][5]["args"][1]["layout"], json!({
+		"sequence": { "tuple": ["u32", "str"] },
+	}));
+}
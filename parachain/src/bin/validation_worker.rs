@@ -0,0 +1,38 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Standalone validation worker, started by a `ValidationPool` configured with
+//! `ValidationPoolConfig::with_worker_binary`.
+//!
+//! Takes the id passed by the pool as its only argument.
+
+use std::{env, process};
+use powerplay_parachain::wasm_executor::run_worker;
+
+fn main() {
+	let id = match env::args().nth(1) {
+		Some(id) => id,
+		None => {
+			eprintln!("Usage: powerplay-validation-worker <shared memory id | stdio>");
+			process::exit(2);
+		},
+	};
+
+	if let Err(e) = run_worker(&id) {
+		eprintln!("Validation worker failed: {}", e);
+		process::exit(1);
+	}
+}
@@ -0,0 +1,273 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Static checks of validation code.
+//!
+//! These catch code which can never validate a candidate without executing it, so they can be
+//! run when code is registered or upgraded.
+
+use std::collections::BTreeSet;
+use parity_wasm::elements::{
+	External, FunctionType, Instruction, Internal, Module, Type, ValueType,
+};
+use pwasm_utils::rules::InstructionType;
+use sp_wasm_interface::HostFunctions as _;
+use crate::primitives::ValidationCode;
+use super::{HostFunctions, MAX_CODE_MEM};
+use super::instrument::{GAS_FUNCTION, MEMORY_GROWN_FUNCTION};
+
+/// Name of the function validating a candidate, exported by validation code.
+pub const VALIDATE_BLOCK: &str = "validate_block";
+
+/// Name of the global exported by validation code marking the start of the heap.
+const HEAP_BASE: &str = "__heap_base";
+
+/// Information about valid validation code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeInfo {
+	/// Size of the code, in bytes.
+	pub size: usize,
+	/// Initial number of pages of the imported memory.
+	pub initial_memory_pages: u32,
+	/// Maximum number of pages of the imported memory, if limited.
+	pub max_memory_pages: Option<u32>,
+	/// Names of the host functions imported by the code.
+	pub host_functions: BTreeSet<String>,
+	/// Names of the imported host functions which the executor doesn't provide. These trap
+	/// when called.
+	pub missing_host_functions: BTreeSet<String>,
+}
+
+/// Why validation code is invalid.
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+pub enum CodeError {
+	/// The code is larger than `MAX_CODE_MEM`.
+	#[display(fmt = "WASM code is {} bytes, max allowed is {}", _0, MAX_CODE_MEM)]
+	TooLarge(usize),
+	/// The code is not a valid wasm module.
+	#[display(fmt = "Invalid WASM code: {}", _0)]
+	InvalidWasm(String),
+	/// The code doesn't export `validate_block`.
+	#[display(fmt = "`{}` is not exported", VALIDATE_BLOCK)]
+	MissingValidateBlock,
+	/// `validate_block` doesn't have the signature `(i32, i32) -> i64`.
+	#[display(fmt = "`{}` has the wrong signature", VALIDATE_BLOCK)]
+	BadValidateBlockSignature,
+	/// The code doesn't export `__heap_base`.
+	#[display(fmt = "`{}` is not exported", HEAP_BASE)]
+	MissingHeapBase,
+	/// The code doesn't import `env.memory`, or defines its own memory.
+	#[display(fmt = "Memory is not imported from `env.memory`")]
+	MemoryNotImported,
+	/// The code uses floating-point values.
+	#[display(fmt = "Floating-point values are not allowed")]
+	FloatingPoint,
+	/// The code imports something other than memory and host functions.
+	#[display(fmt = "Import of `{}.{}` is not allowed", module, field)]
+	DisallowedImport {
+		module: String,
+		field: String,
+	},
+}
+
+impl std::error::Error for CodeError {}
+
+/// Check that `code` is valid validation code, returning information about it.
+///
+/// Valid code is at most `MAX_CODE_MEM` bytes, imports its memory from `env.memory`, only
+/// imports functions from `env`, exports `validate_block` and `__heap_base`, and doesn't use
+/// floating-point values.
+pub fn validate_code(code: &ValidationCode) -> Result<CodeInfo, CodeError> {
+	check_code(&code.0)
+}
+
+/// Like `validate_code`, for code which isn't wrapped in `ValidationCode`.
+pub(crate) fn check_code(code: &[u8]) -> Result<CodeInfo, CodeError> {
+	if code.len() > MAX_CODE_MEM {
+		return Err(CodeError::TooLarge(code.len()));
+	}
+
+	let module = parity_wasm::deserialize_buffer::<Module>(code)
+		.map_err(|e| CodeError::InvalidWasm(e.to_string()))?;
+	let types = module.type_section().map_or(&[][..], |section| section.types());
+	let function_type = |index: u32| match types.get(index as usize) {
+		Some(Type::Function(ty)) => Some(ty),
+		None => None,
+	};
+
+	let known: BTreeSet<&str> = HostFunctions::host_functions().iter().map(|f| f.name()).collect();
+	let mut memory = None;
+	let mut host_functions = BTreeSet::new();
+	let mut imported_types = Vec::new();
+	for entry in module.import_section().map_or(&[][..], |section| section.entries()) {
+		let disallowed = || CodeError::DisallowedImport {
+			module: entry.module().into(),
+			field: entry.field().into(),
+		};
+
+		if entry.module() != "env" {
+			return Err(disallowed());
+		}
+
+		match entry.external() {
+			External::Memory(ty) if entry.field() == "memory" => memory = Some(*ty.limits()),
+			External::Function(index) => {
+				if entry.field() == GAS_FUNCTION || entry.field() == MEMORY_GROWN_FUNCTION {
+					return Err(disallowed());
+				}
+				let ty = function_type(*index)
+					.ok_or_else(|| CodeError::InvalidWasm("Unknown function type".into()))?;
+				if has_float(ty) {
+					return Err(CodeError::FloatingPoint);
+				}
+				imported_types.push(ty);
+				host_functions.insert(entry.field().to_string());
+			},
+			_ => return Err(disallowed()),
+		}
+	}
+
+	let memory = match memory {
+		Some(memory) if module.memory_section().is_none() => memory,
+		_ => return Err(CodeError::MemoryNotImported),
+	};
+
+	// Types of all functions, imported ones first.
+	let defined_types = module.function_section()
+		.map_or(&[][..], |section| section.entries())
+		.iter()
+		.map(|func| function_type(func.type_ref())
+			.ok_or_else(|| CodeError::InvalidWasm("Unknown function type".into())))
+		.collect::<Result<Vec<_>, _>>()?;
+	if defined_types.iter().any(|ty| has_float(ty)) {
+		return Err(CodeError::FloatingPoint);
+	}
+
+	let exports = module.export_section().map_or(&[][..], |section| section.entries());
+	let validate_block = exports.iter()
+		.find_map(|entry| match entry.internal() {
+			Internal::Function(index) if entry.field() == VALIDATE_BLOCK => Some(*index as usize),
+			_ => None,
+		})
+		.ok_or(CodeError::MissingValidateBlock)?;
+	let validate_block = imported_types.iter().chain(defined_types.iter())
+		.nth(validate_block)
+		.ok_or(CodeError::MissingValidateBlock)?;
+	if validate_block.params() != [ValueType::I32, ValueType::I32]
+		|| validate_block.return_type() != Some(ValueType::I64)
+	{
+		return Err(CodeError::BadValidateBlockSignature);
+	}
+
+	let exports_heap_base = exports.iter()
+		.any(|entry| entry.field() == HEAP_BASE && matches!(entry.internal(), Internal::Global(_)));
+	if !exports_heap_base {
+		return Err(CodeError::MissingHeapBase);
+	}
+
+	let float_globals = module.global_section()
+		.map_or(&[][..], |section| section.entries())
+		.iter()
+		.any(|global| is_float(global.global_type().content_type()));
+	let float_code = module.code_section()
+		.map_or(&[][..], |section| section.bodies())
+		.iter()
+		.any(|body| {
+			body.locals().iter().any(|local| is_float(local.value_type()))
+				|| body.code().elements().iter().any(is_float_instruction)
+		});
+	if float_globals || float_code {
+		return Err(CodeError::FloatingPoint);
+	}
+
+	let missing_host_functions = host_functions.iter()
+		.filter(|name| !known.contains(name.as_str()))
+		.cloned()
+		.collect();
+
+	Ok(CodeInfo {
+		size: code.len(),
+		initial_memory_pages: memory.initial(),
+		max_memory_pages: memory.maximum(),
+		host_functions,
+		missing_host_functions,
+	})
+}
+
+/// Information about `code` needed to prepare it for execution.
+///
+/// Unlike `check_code`, this doesn't reject code: validation code is only checked when it is
+/// registered or upgraded, so code registered before a check was added keeps executing.
+pub(crate) fn inspect_code(code: &[u8]) -> Result<CodeInfo, CodeError> {
+	let module = parity_wasm::deserialize_buffer::<Module>(code)
+		.map_err(|e| CodeError::InvalidWasm(e.to_string()))?;
+
+	let known: BTreeSet<&str> = HostFunctions::host_functions().iter().map(|f| f.name()).collect();
+	let mut memory = module.memory_section()
+		.and_then(|section| section.entries().first())
+		.map(|memory| *memory.limits());
+	let mut host_functions = BTreeSet::new();
+	for entry in module.import_section().map_or(&[][..], |section| section.entries()) {
+		match entry.external() {
+			External::Memory(ty) => memory = Some(*ty.limits()),
+			External::Function(_) if entry.module() == "env" => {
+				host_functions.insert(entry.field().to_string());
+			},
+			_ => {},
+		}
+	}
+
+	let missing_host_functions = host_functions.iter()
+		.filter(|name| !known.contains(name.as_str()))
+		.cloned()
+		.collect();
+
+	Ok(CodeInfo {
+		size: code.len(),
+		initial_memory_pages: memory.map_or(0, |memory| memory.initial()),
+		max_memory_pages: memory.and_then(|memory| memory.maximum()),
+		host_functions,
+		missing_host_functions,
+	})
+}
+
+fn is_float(ty: ValueType) -> bool {
+	match ty {
+		ValueType::F32 | ValueType::F64 => true,
+		_ => false,
+	}
+}
+
+fn has_float(ty: &FunctionType) -> bool {
+	ty.params().iter().cloned().any(is_float) || ty.return_type().map_or(false, is_float)
+}
+
+fn is_float_instruction(instruction: &Instruction) -> bool {
+	match instruction {
+		Instruction::F32Load(..) | Instruction::F64Load(..)
+			| Instruction::F32Store(..) | Instruction::F64Store(..) => return true,
+		_ => {},
+	}
+
+	match InstructionType::op(instruction) {
+		InstructionType::Float
+			| InstructionType::FloatComparsion
+			| InstructionType::FloatConst
+			| InstructionType::FloatConversion
+			| InstructionType::Reinterpretation => true,
+		_ => false,
+	}
+}
@@ -0,0 +1,344 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Preparation and execution of validation code.
+//!
+//! Preparing validation code parses it and, with the compiled execution method, compiles it to
+//! native code. This is the most expensive part of validating small candidates, so an `Executor`
+//! keeps the most recently used modules in a cache keyed by the hash of the code.
+//!
+//! Code is instrumented before it is prepared, so it can only execute as many instructions as
+//! the fuel limit allows and use as much memory as the memory limit allows.
+//!
+//! The checks of `validate_code` are not run when preparing code, they belong to registration:
+//! code which passed an earlier version of them must keep executing after a restart or after
+//! being evicted from the cache.
+
+use std::{collections::HashMap, panic, sync::Arc, time::{Duration, Instant}};
+use codec::{Decode, Encode};
+use log::warn;
+use parking_lot::Mutex;
+use sc_executor_common::wasm_runtime::WasmModule;
+use sp_externalities::ExternalitiesExt;
+use super::{code, Error};
+use super::instrument::{self, ExecutionMeter, ExecutionMeterExt};
+
+/// Number of prepared modules an `Executor` keeps by default.
+pub const DEFAULT_CACHE_SIZE: usize = 16;
+
+/// Number of heap pages available to validation code by default.
+pub const DEFAULT_HEAP_PAGES: u64 = 1024;
+
+/// Maximum number of wasm pages validation code can use by default: 1 GiB.
+pub const DEFAULT_MAX_MEMORY_PAGES: u32 = 16 * 1024;
+
+/// Fuel available to validation code by default. One unit of fuel is used per executed
+/// instruction.
+pub const DEFAULT_FUEL_LIMIT: u64 = 2_000_000_000;
+
+/// How validation code is executed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Encode, Decode)]
+pub enum ExecutionMethod {
+	/// Interpret the code with `wasmi`.
+	Interpreted,
+	/// Compile the code to native code with `wasmtime`.
+	///
+	/// > Note: Only available with the `wasmtime` feature.
+	Compiled,
+}
+
+/// What to do with validation code importing host functions the executor doesn't provide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Encode, Decode)]
+pub enum HostFunctionPolicy {
+	/// Accept the code. Calling a missing host function traps.
+	Allow,
+	/// Reject the code before instantiating it.
+	Deny,
+	/// Like `Allow`, but log the missing host functions when the code is prepared.
+	Report,
+}
+
+/// Parameters of validation code execution.
+///
+/// These are sent along with every candidate to remote validation workers.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct ExecutorParams {
+	/// The execution method.
+	pub method: ExecutionMethod,
+	/// Number of heap pages available to the code, which the executor allocates from.
+	pub heap_pages: u64,
+	/// Maximum number of wasm pages the code can use, including the heap pages. Candidates
+	/// exceeding it are invalid, so every validator must use the same limit.
+	pub max_memory_pages: u32,
+	/// Fuel available to a single execution, see `DEFAULT_FUEL_LIMIT`. Candidates running out
+	/// of it are invalid, so every validator must use the same limit.
+	pub fuel_limit: u64,
+	/// What to do with code importing host functions which aren't provided.
+	pub missing_host_functions: HostFunctionPolicy,
+	/// Names of the `sp_io` and `parachain` host functions provided to the code. All of them are
+	/// provided if `None`.
+	pub allowed_host_functions: Option<Vec<String>>,
+}
+
+impl Default for ExecutorParams {
+	fn default() -> Self {
+		ExecutorParams {
+			method: ExecutionMethod::Interpreted,
+			heap_pages: DEFAULT_HEAP_PAGES,
+			max_memory_pages: DEFAULT_MAX_MEMORY_PAGES,
+			fuel_limit: DEFAULT_FUEL_LIMIT,
+			missing_host_functions: HostFunctionPolicy::Allow,
+			allowed_host_functions: None,
+		}
+	}
+}
+
+/// Statistics of an execution of validation code.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub struct ExecutionStats {
+	/// Time the execution took, including preparing the code if it wasn't cached.
+	pub wall_time: Duration,
+	/// Fuel used by the execution.
+	pub fuel_used: u64,
+	/// Largest number of wasm pages the memory had during the execution.
+	pub peak_memory_pages: u32,
+	/// Number of upward messages posted.
+	pub upward_messages: u32,
+	/// Total size of the data of the upward messages posted, in bytes.
+	pub upward_message_bytes: u64,
+	/// Whether the prepared code was found in the executor's cache.
+	pub cache_hit: bool,
+}
+
+/// Validation code prepared for execution.
+#[derive(Clone)]
+pub(crate) struct PreparedModule {
+	module: Arc<dyn WasmModule>,
+	/// Number of wasm pages of the memory of a new instance, including the heap pages.
+	memory_pages: u32,
+}
+
+struct CacheEntry {
+	module: PreparedModule,
+	last_used: u64,
+}
+
+/// Prepared modules by code hash, evicting the least recently used.
+struct ModuleCache {
+	entries: HashMap<[u8; 32], CacheEntry>,
+	capacity: usize,
+	tick: u64,
+}
+
+impl ModuleCache {
+	fn get(&mut self, hash: &[u8; 32]) -> Option<PreparedModule> {
+		self.tick += 1;
+		let tick = self.tick;
+		self.entries.get_mut(hash).map(|entry| {
+			entry.last_used = tick;
+			entry.module.clone()
+		})
+	}
+
+	fn insert(&mut self, hash: [u8; 32], module: PreparedModule) {
+		if self.capacity == 0 {
+			return;
+		}
+
+		if self.entries.len() >= self.capacity && !self.entries.contains_key(&hash) {
+			let oldest = self.entries.iter()
+				.min_by_key(|(_, entry)| entry.last_used)
+				.map(|(hash, _)| *hash);
+			if let Some(oldest) = oldest {
+				self.entries.remove(&oldest);
+			}
+		}
+
+		self.tick += 1;
+		self.entries.insert(hash, CacheEntry { module, last_used: self.tick });
+	}
+}
+
+/// Executes validation code, caching prepared modules.
+///
+/// Clones share the same cache.
+#[derive(Clone)]
+pub struct Executor {
+	params: ExecutorParams,
+	cache: Arc<Mutex<ModuleCache>>,
+}
+
+impl Default for Executor {
+	fn default() -> Self {
+		Executor::new(ExecutorParams::default())
+	}
+}
+
+impl Executor {
+	/// Create an executor with the given parameters and a cache of `DEFAULT_CACHE_SIZE` modules.
+	pub fn new(params: ExecutorParams) -> Self {
+		Executor::with_cache_size(params, DEFAULT_CACHE_SIZE)
+	}
+
+	/// Create an executor with the given parameters, caching up to `cache_size` modules.
+	pub fn with_cache_size(params: ExecutorParams, cache_size: usize) -> Self {
+		Executor {
+			params,
+			cache: Arc::new(Mutex::new(ModuleCache {
+				entries: HashMap::new(),
+				capacity: cache_size,
+				tick: 0,
+			})),
+		}
+	}
+
+	/// The parameters of this executor.
+	pub fn params(&self) -> &ExecutorParams {
+		&self.params
+	}
+
+	/// Get the prepared module for `code`, preparing it if it isn't cached.
+	///
+	/// Also returns whether the module was cached.
+	pub(crate) fn prepare(&self, code: &[u8]) -> Result<(PreparedModule, bool), Error> {
+		let hash = sp_core::hashing::blake2_256(code);
+		if let Some(module) = self.cache.lock().get(&hash) {
+			return Ok((module, true));
+		}
+
+		// Don't hold the lock while preparing, other threads may use other cached modules.
+		let module = self.create_module(code)?;
+		self.cache.lock().insert(hash, module.clone());
+		Ok((module, false))
+	}
+
+	fn create_module(&self, code: &[u8]) -> Result<PreparedModule, Error> {
+		let info = code::inspect_code(code)?;
+		let allowed = self.params.allowed_host_functions.as_deref();
+		let host_functions = instrument::host_functions(allowed);
+
+		let missing: Vec<_> = info.host_functions.iter()
+			.filter(|name| !host_functions.iter().any(|f| f.name() == name.as_str()))
+			.cloned()
+			.collect();
+		if !missing.is_empty() {
+			match self.params.missing_host_functions {
+				HostFunctionPolicy::Allow => {},
+				HostFunctionPolicy::Deny => return Err(Error::MissingHostFunctions(missing)),
+				HostFunctionPolicy::Report =>
+					warn!("Validation code imports missing host functions: {}", missing.join(", ")),
+			}
+		}
+		let allow_missing = self.params.missing_host_functions != HostFunctionPolicy::Deny;
+
+		let code = instrument::instrument(code, &self.params)?;
+		let code = &code[..];
+
+		let module: Arc<dyn WasmModule> = match self.params.method {
+			ExecutionMethod::Interpreted => Arc::new(sc_executor_wasmi::create_runtime(
+				code,
+				self.params.heap_pages,
+				host_functions,
+				allow_missing,
+			)?),
+			#[cfg(feature = "wasmtime")]
+			ExecutionMethod::Compiled => Arc::new(sc_executor_wasmtime::create_runtime(
+				code,
+				self.params.heap_pages,
+				host_functions,
+				allow_missing,
+			)?),
+			#[cfg(not(feature = "wasmtime"))]
+			ExecutionMethod::Compiled => return Err(Error::External(
+				"Compiled execution requires the `wasmtime` feature".into()
+			)),
+		};
+
+		// The instrumentation checked that this fits under the memory limit.
+		let memory_pages = (info.initial_memory_pages as u64 + self.params.heap_pages) as u32;
+
+		Ok(PreparedModule { module, memory_pages })
+	}
+
+	/// Call `method` of `code` with `data`, using `ext` as the externalities, returning the
+	/// result along with statistics of the call. The statistics don't count upward messages.
+	///
+	/// Fails with `Error::OutOfFuel` if the call uses more than the fuel limit, and with
+	/// `Error::MemoryLimitExceeded` if it uses more memory than the limit.
+	pub(crate) fn call(
+		&self,
+		code: &[u8],
+		method: &str,
+		data: &[u8],
+		mut ext: &mut dyn sp_externalities::Externalities,
+	) -> Result<(Vec<u8>, ExecutionStats), Error> {
+		let start = Instant::now();
+		let (prepared, cache_hit) = self.prepare(code)?;
+		let instance = prepared.module.new_instance()?;
+
+		let meter = ExecutionMeter::new(
+			self.params.fuel_limit,
+			self.params.max_memory_pages,
+			prepared.memory_pages,
+		);
+		ext.register_extension(ExecutionMeterExt(meter))
+			.map_err(|e| Error::External(format!("Can't register the execution meter: {:?}", e)))?;
+
+		// Host functions panic on unsupported externalities, which must not take down the node.
+		let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+			sp_externalities::set_and_run_with_externalities(&mut *ext, || {
+				instance.call(method, data)
+			})
+		}));
+
+		let (out_of_fuel, out_of_memory, fuel_used, peak_memory_pages) = ext
+			.extension::<ExecutionMeterExt>()
+			.map_or((false, false, 0, prepared.memory_pages), |meter| (
+				meter.out_of_fuel(),
+				meter.out_of_memory(),
+				meter.fuel_used(),
+				meter.peak_memory_pages(),
+			));
+		let _ = ext.deregister_extension::<ExecutionMeterExt>();
+
+		// The code traps when it runs out of fuel or memory, which is reported instead of the trap.
+		if out_of_fuel {
+			return Err(Error::OutOfFuel);
+		}
+		if out_of_memory {
+			return Err(Error::MemoryLimitExceeded);
+		}
+
+		let stats = ExecutionStats {
+			wall_time: start.elapsed(),
+			fuel_used,
+			peak_memory_pages,
+			upward_messages: 0,
+			upward_message_bytes: 0,
+			cache_hit,
+		};
+
+		match result {
+			Ok(result) => result.map(|output| (output, stats)).map_err(Into::into),
+			Err(panic) => {
+				let message = panic.downcast_ref::<&str>().map(|s| s.to_string())
+					.or_else(|| panic.downcast_ref::<String>().cloned())
+					.unwrap_or_else(|| "unknown panic".into());
+				Err(sc_executor::error::Error::RuntimePanicked(message).into())
+			},
+		}
+	}
+}
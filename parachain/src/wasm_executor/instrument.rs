@@ -0,0 +1,567 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Instrumentation of validation code.
+//!
+//! Before execution, validation code is instrumented to:
+//!
+//! - charge fuel for every executed instruction through the `env.gas` host function.
+//! - cap its linear memory at the configured number of pages, and report every `memory.grow`
+//!   through the `env.memory_grown` host function, so a grow failing because it would pass the
+//!   limit traps. Other failed grows return -1 to the code as usual.
+//!
+//! Both are tracked by the `ExecutionMeter` extension, so running out of fuel or memory fails at
+//! the same point on every machine. Host functions are wrapped to also notice the executor's
+//! allocator running out of heap pages, and to record their calls when tracing.
+
+use std::cell::Cell;
+use once_cell::sync::Lazy;
+use parity_wasm::elements::{
+	External, FuncBody, Func, FunctionType, ImportCountType, ImportEntry, Instruction,
+	Instructions, Internal, Local, MemoryType, Module, Type, ValueType as WasmType,
+};
+use sp_externalities::ExternalitiesExt;
+use sp_wasm_interface::{
+	Function, FunctionContext, HostFunctions as _, Pointer, Sandbox, Signature, Value, ValueType,
+	WordSize,
+};
+use super::{Error, ExecutorParams, HostFunctions};
+use super::trace::{HostCall, LogLine, TraceEvent, TraceExt, LOG_FUNCTION};
+
+/// Name of the host function charging fuel, imported from `env`.
+pub(crate) const GAS_FUNCTION: &str = "gas";
+
+/// Name of the host function called after every `memory.grow`, imported from `env`.
+pub(crate) const MEMORY_GROWN_FUNCTION: &str = "memory_grown";
+
+/// Number of bytes of the target and of the message of a log line recorded in a trace.
+const MAX_LOG_LINE_BYTES: usize = 4096;
+
+sp_externalities::decl_extension! {
+	/// The extension tracking the resources used by validation code.
+	pub(crate) struct ExecutionMeterExt(ExecutionMeter);
+}
+
+/// Resources used by an execution.
+pub(crate) struct ExecutionMeter {
+	fuel_limit: u64,
+	memory_limit: u32,
+	fuel_used: u64,
+	peak_memory_pages: u32,
+	out_of_fuel: bool,
+	out_of_memory: bool,
+}
+
+impl ExecutionMeter {
+	/// Create a meter for an execution starting with `memory_pages` wasm pages of memory, which
+	/// can use `fuel_limit` fuel and grow up to `memory_limit` pages.
+	pub(crate) fn new(fuel_limit: u64, memory_limit: u32, memory_pages: u32) -> Self {
+		ExecutionMeter {
+			fuel_limit,
+			memory_limit,
+			fuel_used: 0,
+			peak_memory_pages: memory_pages,
+			out_of_fuel: false,
+			out_of_memory: false,
+		}
+	}
+
+	/// Fuel used by the execution so far.
+	pub(crate) fn fuel_used(&self) -> u64 {
+		self.fuel_used
+	}
+
+	/// Largest number of wasm pages the memory had so far.
+	pub(crate) fn peak_memory_pages(&self) -> u32 {
+		self.peak_memory_pages
+	}
+
+	/// Whether the execution ran out of fuel.
+	pub(crate) fn out_of_fuel(&self) -> bool {
+		self.out_of_fuel
+	}
+
+	/// Whether the execution hit the memory limit.
+	pub(crate) fn out_of_memory(&self) -> bool {
+		self.out_of_memory
+	}
+
+	fn charge(&mut self, amount: u64) -> Result<(), String> {
+		match self.fuel_used.checked_add(amount) {
+			Some(used) if used <= self.fuel_limit => {
+				self.fuel_used = used;
+				Ok(())
+			},
+			_ => {
+				self.fuel_used = self.fuel_limit;
+				self.out_of_fuel = true;
+				Err("Out of fuel".into())
+			},
+		}
+	}
+}
+
+/// Run `f` with the meter of the current execution.
+fn with_meter<R>(f: impl FnOnce(&mut ExecutionMeter) -> Result<R, String>) -> Result<R, String> {
+	sp_externalities::with_externalities(|mut ext| {
+		ext.extension::<ExecutionMeterExt>()
+			.ok_or_else(|| "No `ExecutionMeterExt` associated with the current context".to_string())
+			.and_then(|meter| f(meter))
+	})
+	.unwrap_or_else(|| Err("No externalities associated with the current context".into()))
+}
+
+/// Instrument `code` for execution with `params`, returning the instrumented code.
+pub(crate) fn instrument(code: &[u8], params: &ExecutorParams) -> Result<Vec<u8>, Error> {
+	let invalid = |e: String| Error::WasmModule(sc_executor::error::WasmError::Other(e));
+
+	let module: Module = parity_wasm::deserialize_buffer(code)
+		.map_err(|e| invalid(format!("Can't decode wasm code: {:?}", e)))?;
+
+	// The instrumentation imports these, the code must not import them itself.
+	let imports_reserved = module.import_section()
+		.map(|section| section.entries().iter().any(|entry| {
+			entry.module() == "env"
+				&& (entry.field() == GAS_FUNCTION || entry.field() == MEMORY_GROWN_FUNCTION)
+		}))
+		.unwrap_or(false);
+	if imports_reserved {
+		return Err(invalid("Code imports a reserved function".into()));
+	}
+
+	let module = limit_memory(module, params)?;
+	let module = inject_grow_hook(module).map_err(invalid)?;
+	let module = pwasm_utils::inject_gas_counter(module, &pwasm_utils::rules::Set::default())
+		.map_err(|_| invalid("Can't inject the fuel counter".into()))?;
+
+	parity_wasm::serialize(module).map_err(|e| invalid(format!("Can't encode wasm code: {:?}", e)))
+}
+
+/// Cap the maximum of every memory at `params.max_memory_pages`.
+///
+/// The executor adds `params.heap_pages` to the initial size of the memory, so these need to fit
+/// under the cap as well.
+fn limit_memory(mut module: Module, params: &ExecutorParams) -> Result<Module, Error> {
+	let limit = params.max_memory_pages;
+	let cap = |memory: &mut MemoryType| {
+		let initial = memory.limits().initial();
+		if initial as u64 + params.heap_pages > limit as u64 {
+			return Err(Error::MemoryLimitExceeded);
+		}
+
+		let maximum = memory.limits().maximum().map_or(limit, |maximum| maximum.min(limit));
+		*memory = MemoryType::new(initial, Some(maximum));
+		Ok(())
+	};
+
+	if let Some(section) = module.import_section_mut() {
+		for entry in section.entries_mut() {
+			if let External::Memory(memory) = entry.external_mut() {
+				cap(memory)?;
+			}
+		}
+	}
+
+	if let Some(section) = module.memory_section_mut() {
+		for memory in section.entries_mut() {
+			cap(memory)?;
+		}
+	}
+
+	Ok(module)
+}
+
+/// Replace every `memory.grow` with a call to a function growing the memory and passing the
+/// result to `env.memory_grown`.
+fn inject_grow_hook(mut module: Module) -> Result<Module, String> {
+	let grows = module.code_section()
+		.map(|section| section.bodies().iter().any(|body| {
+			body.code().elements().iter().any(|i| matches!(i, Instruction::GrowMemory(_)))
+		}))
+		.unwrap_or(false);
+	if !grows {
+		return Ok(module);
+	}
+
+	let types = module.type_section_mut()
+		.ok_or_else(|| "Code without a type section".to_string())?
+		.types_mut();
+	let grown_type = types.len() as u32;
+	types.push(Type::Function(FunctionType::new(vec![WasmType::I32, WasmType::I32], None)));
+	let hook_type = types.len() as u32;
+	types.push(Type::Function(FunctionType::new(vec![WasmType::I32], Some(WasmType::I32))));
+
+	// The import goes after all imported functions, shifting every defined function by one.
+	let grown_index = module.import_count(ImportCountType::Function) as u32;
+	module.import_section_mut()
+		.ok_or_else(|| "Code without an import section".to_string())?
+		.entries_mut()
+		.push(ImportEntry::new(
+			"env".into(),
+			MEMORY_GROWN_FUNCTION.into(),
+			External::Function(grown_type),
+		));
+
+	let shift = |index: &mut u32| if *index >= grown_index { *index += 1 };
+	let defined = module.function_section().map_or(0, |section| section.entries().len()) as u32;
+	let hook_index = grown_index + 1 + defined;
+
+	if let Some(section) = module.code_section_mut() {
+		for body in section.bodies_mut() {
+			for instruction in body.code_mut().elements_mut() {
+				match instruction {
+					Instruction::Call(index) => shift(index),
+					Instruction::GrowMemory(_) => *instruction = Instruction::Call(hook_index),
+					_ => {},
+				}
+			}
+		}
+	}
+
+	if let Some(section) = module.export_section_mut() {
+		for entry in section.entries_mut() {
+			if let Internal::Function(index) = entry.internal_mut() {
+				shift(index);
+			}
+		}
+	}
+
+	if let Some(section) = module.elements_section_mut() {
+		for segment in section.entries_mut() {
+			segment.members_mut().iter_mut().for_each(shift);
+		}
+	}
+
+	if let Some(mut start) = module.start_section() {
+		shift(&mut start);
+		module.set_start_section(start);
+	}
+
+	module.function_section_mut()
+		.ok_or_else(|| "Code without a function section".to_string())?
+		.entries_mut()
+		.push(Func::new(hook_type));
+	module.code_section_mut()
+		.ok_or_else(|| "Code without a code section".to_string())?
+		.bodies_mut()
+		.push(FuncBody::new(
+			vec![Local::new(1, WasmType::I32)],
+			Instructions::new(vec![
+				Instruction::GetLocal(0),
+				Instruction::GrowMemory(0),
+				Instruction::TeeLocal(1),
+				Instruction::GetLocal(0),
+				Instruction::Call(grown_index),
+				Instruction::GetLocal(1),
+				Instruction::End,
+			]),
+		));
+
+	Ok(module)
+}
+
+/// The host functions available to instrumented code: the `sp_io` and `parachain` host
+/// functions named in `allowed`, or all of them if `None`, and those called by the
+/// instrumentation.
+pub(crate) fn host_functions(allowed: Option<&[String]>) -> Vec<&'static dyn Function> {
+	static METERED: Lazy<Vec<Metered>> = Lazy::new(|| {
+		HostFunctions::host_functions().into_iter().map(Metered).collect()
+	});
+
+	METERED.iter()
+		.filter(|f| allowed.map_or(true, |allowed| allowed.iter().any(|name| name == f.name())))
+		.map(|f| f as &'static dyn Function)
+		.chain(vec![&Gas as &'static dyn Function, &MemoryGrown])
+		.collect()
+}
+
+/// A host function whose memory allocations are watched for the heap running out, and whose
+/// calls are recorded if the execution is traced.
+struct Metered(&'static dyn Function);
+
+impl Metered {
+	fn execute_traced(
+		&self,
+		context: &mut dyn FunctionContext,
+		args: Vec<Value>,
+	) -> sp_wasm_interface::Result<Option<Value>> {
+		if self.name() == LOG_FUNCTION {
+			if let Some(line) = read_log_line(context, &args) {
+				with_trace(|trace| {
+					let time = trace.elapsed();
+					trace.push(TraceEvent::Log(LogLine { time, ..line }));
+				});
+			}
+		}
+
+		let start = with_trace(|trace| trace.elapsed()).unwrap_or_default();
+		let mut context = MeteredContext::new(context);
+		let result = self.0.execute(&mut context, &mut args.clone().into_iter());
+
+		// The trace can't be borrowed during the call, the host function may use the externalities.
+		with_trace(|trace| {
+			let duration = trace.elapsed() - start;
+			trace.push(TraceEvent::HostCall(HostCall {
+				name: self.name().into(),
+				args,
+				result: result.clone(),
+				bytes_read: context.bytes_read.get(),
+				bytes_written: context.bytes_written,
+				start,
+				duration,
+			}));
+		});
+
+		result
+	}
+}
+
+impl Function for Metered {
+	fn name(&self) -> &str {
+		self.0.name()
+	}
+
+	fn signature(&self) -> Signature {
+		self.0.signature()
+	}
+
+	fn execute(
+		&self,
+		context: &mut dyn FunctionContext,
+		args: &mut dyn Iterator<Item = Value>,
+	) -> sp_wasm_interface::Result<Option<Value>> {
+		if with_trace(|_| ()).is_some() {
+			return self.execute_traced(context, args.collect());
+		}
+
+		self.0.execute(&mut MeteredContext::new(context), args)
+	}
+}
+
+/// Run `f` with the trace of the current execution, if it is traced.
+fn with_trace<R>(f: impl FnOnce(&mut super::trace::TraceRecorder) -> R) -> Option<R> {
+	sp_externalities::with_externalities(|mut ext| {
+		ext.extension::<TraceExt>().map(|trace| f(trace))
+	})
+	.flatten()
+}
+
+/// Read the line logged by a call of `LOG_FUNCTION` with `args`, whose target and message are
+/// passed as pointer-size pairs.
+///
+/// The sizes are chosen by the code, so only the first `MAX_LOG_LINE_BYTES` bytes of the target
+/// and of the message are read.
+fn read_log_line(context: &dyn FunctionContext, args: &[Value]) -> Option<LogLine> {
+	let read = |pointer_size: i64| {
+		let pointer = pointer_size as u64 as u32;
+		let size = ((pointer_size as u64 >> 32) as usize).min(MAX_LOG_LINE_BYTES);
+		let mut data = vec![0; size];
+		context.read_memory_into(Pointer::new(pointer), &mut data).ok()?;
+		Some(String::from_utf8_lossy(&data).into_owned())
+	};
+
+	match args {
+		[Value::I32(level), Value::I64(target), Value::I64(message)] => Some(LogLine {
+			level: *level as u32,
+			target: read(*target)?,
+			message: read(*message)?,
+			time: Default::default(),
+		}),
+		_ => None,
+	}
+}
+
+struct MeteredContext<'a> {
+	context: &'a mut dyn FunctionContext,
+	bytes_read: Cell<u64>,
+	bytes_written: u64,
+}
+
+impl<'a> MeteredContext<'a> {
+	fn new(context: &'a mut dyn FunctionContext) -> Self {
+		MeteredContext { context, bytes_read: Cell::new(0), bytes_written: 0 }
+	}
+}
+
+impl FunctionContext for MeteredContext<'_> {
+	fn read_memory_into(
+		&self,
+		address: Pointer<u8>,
+		dest: &mut [u8],
+	) -> sp_wasm_interface::Result<()> {
+		self.bytes_read.set(self.bytes_read.get() + dest.len() as u64);
+		self.context.read_memory_into(address, dest)
+	}
+
+	fn write_memory(&mut self, address: Pointer<u8>, data: &[u8]) -> sp_wasm_interface::Result<()> {
+		self.bytes_written += data.len() as u64;
+		self.context.write_memory(address, data)
+	}
+
+	fn allocate_memory(&mut self, size: WordSize) -> sp_wasm_interface::Result<Pointer<u8>> {
+		self.context.allocate_memory(size).map_err(|e| {
+			let _ = with_meter(|meter| {
+				meter.out_of_memory = true;
+				Ok(())
+			});
+			e
+		})
+	}
+
+	fn deallocate_memory(&mut self, ptr: Pointer<u8>) -> sp_wasm_interface::Result<()> {
+		self.context.deallocate_memory(ptr)
+	}
+
+	fn sandbox(&mut self) -> &mut dyn Sandbox {
+		self.context.sandbox()
+	}
+}
+
+/// `env.gas(amount: i32)`, charging `amount` fuel.
+struct Gas;
+
+impl Function for Gas {
+	fn name(&self) -> &str {
+		GAS_FUNCTION
+	}
+
+	fn signature(&self) -> Signature {
+		Signature::new(&[ValueType::I32][..], None)
+	}
+
+	fn execute(
+		&self,
+		_: &mut dyn FunctionContext,
+		args: &mut dyn Iterator<Item = Value>,
+	) -> sp_wasm_interface::Result<Option<Value>> {
+		let amount = match args.next() {
+			Some(Value::I32(amount)) => amount as u32 as u64,
+			_ => return Err("Invalid arguments to `gas`".into()),
+		};
+
+		with_meter(|meter| meter.charge(amount)).map(|_| None)
+	}
+}
+
+/// `env.memory_grown(previous: i32, delta: i32)`, called with the result of every
+/// `memory.grow`. Traps if the memory couldn't grow because it would pass the limit.
+struct MemoryGrown;
+
+impl Function for MemoryGrown {
+	fn name(&self) -> &str {
+		MEMORY_GROWN_FUNCTION
+	}
+
+	fn signature(&self) -> Signature {
+		Signature::new(&[ValueType::I32, ValueType::I32][..], None)
+	}
+
+	fn execute(
+		&self,
+		_: &mut dyn FunctionContext,
+		args: &mut dyn Iterator<Item = Value>,
+	) -> sp_wasm_interface::Result<Option<Value>> {
+		let (previous, delta) = match (args.next(), args.next()) {
+			(Some(Value::I32(previous)), Some(Value::I32(delta))) => (previous, delta),
+			_ => return Err("Invalid arguments to `memory_grown`".into()),
+		};
+
+		// Memory never shrinks, so its size is the peak. Grows failing below the limit, e.g. past
+		// a smaller maximum of the code itself, return -1 to the code.
+		if previous == -1 {
+			with_meter(|meter| {
+				if meter.peak_memory_pages.saturating_add(delta as u32) > meter.memory_limit {
+					meter.out_of_memory = true;
+					Err("Memory limit exceeded".into())
+				} else {
+					Ok(None)
+				}
+			})
+		} else {
+			with_meter(|meter| {
+				let pages = (previous as u32).saturating_add(delta as u32);
+				meter.peak_memory_pages = meter.peak_memory_pages.max(pages);
+				Ok(None)
+			})
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A context whose memory is `memory`.
+	struct Context {
+		memory: Vec<u8>,
+	}
+
+	impl FunctionContext for Context {
+		fn read_memory_into(
+			&self,
+			address: Pointer<u8>,
+			dest: &mut [u8],
+		) -> sp_wasm_interface::Result<()> {
+			let start = u32::from(address) as usize;
+			let data = self.memory.get(start..start + dest.len()).ok_or("Out of bounds")?;
+			dest.copy_from_slice(data);
+			Ok(())
+		}
+
+		fn write_memory(&mut self, _: Pointer<u8>, _: &[u8]) -> sp_wasm_interface::Result<()> {
+			panic!("not used by the test")
+		}
+
+		fn allocate_memory(&mut self, _: WordSize) -> sp_wasm_interface::Result<Pointer<u8>> {
+			panic!("not used by the test")
+		}
+
+		fn deallocate_memory(&mut self, _: Pointer<u8>) -> sp_wasm_interface::Result<()> {
+			panic!("not used by the test")
+		}
+
+		fn sandbox(&mut self) -> &mut dyn Sandbox {
+			panic!("not used by the test")
+		}
+	}
+
+	fn pointer_size(pointer: u32, size: u32) -> Value {
+		Value::I64(((size as u64) << 32 | pointer as u64) as i64)
+	}
+
+	#[test]
+	fn log_lines_are_read() {
+		let context = Context { memory: b"runtimehello".to_vec() };
+		let args = [Value::I32(3), pointer_size(0, 7), pointer_size(7, 5)];
+
+		let line = read_log_line(&context, &args).unwrap();
+		assert_eq!(line.level, 3);
+		assert_eq!(line.target, "runtime");
+		assert_eq!(line.message, "hello");
+	}
+
+	#[test]
+	fn log_lines_are_truncated() {
+		let context = Context { memory: vec![b'a'; MAX_LOG_LINE_BYTES * 2] };
+		let args = [Value::I32(3), pointer_size(0, 1), pointer_size(0, u32::max_value())];
+
+		let line = read_log_line(&context, &args).unwrap();
+		assert_eq!(line.message.len(), MAX_LOG_LINE_BYTES);
+
+		let context = Context { memory: vec![b'a'; 16] };
+		assert!(read_log_line(&context, &args).is_none());
+	}
+}
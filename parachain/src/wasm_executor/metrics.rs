@@ -0,0 +1,174 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Metrics of validation pools.
+//!
+//! A node plugs in its metrics exporter by implementing `MetricsRegistry`, and passes the
+//! `ValidationMetrics` registered with it to a pool in `ValidationPoolConfig::metrics`. The
+//! metrics follow the Prometheus naming conventions.
+
+#![cfg(not(any(target_os = "android", target_os = "unknown")))]
+
+use std::{fmt, sync::Arc, time::Duration};
+use super::{Error, ErrorKind, ValidationOutcome};
+
+/// A counter, partitioned by the values of its labels.
+pub trait Counter: Send + Sync {
+	/// Increment the counter for the given label values, in the order of the label names the
+	/// counter was registered with.
+	fn inc(&self, label_values: &[&str]);
+}
+
+/// A histogram.
+pub trait Histogram: Send + Sync {
+	/// Record a value.
+	fn observe(&self, value: f64);
+}
+
+/// Creates metrics in the registry of an exporter.
+pub trait MetricsRegistry {
+	/// Register a counter with the given name, help text and label names.
+	fn register_counter(
+		&self,
+		name: &str,
+		help: &str,
+		labels: &[&str],
+	) -> Result<Arc<dyn Counter>, String>;
+
+	/// Register a histogram with the given name, help text and bucket upper bounds.
+	fn register_histogram(
+		&self,
+		name: &str,
+		help: &str,
+		buckets: &[f64],
+	) -> Result<Arc<dyn Histogram>, String>;
+}
+
+/// Upper bounds of the buckets of the time histograms, in seconds.
+const TIME_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// The metrics of a validation pool.
+#[derive(Clone)]
+pub struct ValidationMetrics {
+	started: Arc<dyn Counter>,
+	succeeded: Arc<dyn Counter>,
+	failed: Arc<dyn Counter>,
+	queue_wait: Arc<dyn Histogram>,
+	execution_time: Arc<dyn Histogram>,
+	worker_restarts: Arc<dyn Counter>,
+	worker_timeouts: Arc<dyn Counter>,
+}
+
+impl fmt::Debug for ValidationMetrics {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("ValidationMetrics")
+	}
+}
+
+impl ValidationMetrics {
+	/// Register the metrics of a validation pool in `registry`.
+	pub fn register(registry: &dyn MetricsRegistry) -> Result<Self, String> {
+		Ok(ValidationMetrics {
+			started: registry.register_counter(
+				"parachain_validations_started_total",
+				"Number of candidates a validation host started to validate",
+				&[],
+			)?,
+			succeeded: registry.register_counter(
+				"parachain_validations_succeeded_total",
+				"Number of candidates validated successfully",
+				&[],
+			)?,
+			failed: registry.register_counter(
+				"parachain_validations_failed_total",
+				"Number of candidates which failed to validate, by error",
+				&["error"],
+			)?,
+			queue_wait: registry.register_histogram(
+				"parachain_validation_queue_wait_seconds",
+				"Time candidates waited for a validation host",
+				TIME_BUCKETS,
+			)?,
+			execution_time: registry.register_histogram(
+				"parachain_validation_execution_seconds",
+				"Time the validation code of candidates validated successfully executed",
+				TIME_BUCKETS,
+			)?,
+			worker_restarts: registry.register_counter(
+				"parachain_validation_worker_restarts_total",
+				"Number of validation workers started to replace another",
+				&[],
+			)?,
+			worker_timeouts: registry.register_counter(
+				"parachain_validation_worker_timeouts_total",
+				"Number of validation workers killed for exceeding the execution timeout",
+				&[],
+			)?,
+		})
+	}
+
+	/// A host started to validate a candidate which waited `queue_wait` for it.
+	pub(crate) fn on_started(&self, queue_wait: Duration) {
+		self.started.inc(&[]);
+		self.queue_wait.observe(queue_wait.as_secs_f64());
+	}
+
+	/// A host finished validating a candidate.
+	pub(crate) fn on_finished(&self, result: &Result<ValidationOutcome, Error>) {
+		match result {
+			Ok(outcome) => {
+				self.succeeded.inc(&[]);
+				self.execution_time.observe(outcome.stats.wall_time.as_secs_f64());
+			},
+			Err(e) => self.failed.inc(&[error_label(e)]),
+		}
+	}
+
+	pub(crate) fn on_worker_restart(&self) {
+		self.worker_restarts.inc(&[]);
+	}
+
+	pub(crate) fn on_worker_timeout(&self) {
+		self.worker_timeouts.inc(&[]);
+	}
+}
+
+/// The label of the variant of `error`.
+fn error_label(error: &Error) -> &'static str {
+	match error {
+		Error::WasmExecutor(_) => "wasm_executor",
+		Error::InvalidCode(_) => "invalid_code",
+		Error::MissingHostFunctions(_) => "missing_host_functions",
+		Error::WasmModule(_) => "wasm_module",
+		Error::ParamsTooLarge { .. } => "params_too_large",
+		Error::CodeTooLarge { .. } => "code_too_large",
+		Error::MessagesTooLarge { .. } => "messages_too_large",
+		Error::BadReturn => "bad_return",
+		Error::OutOfFuel => "out_of_fuel",
+		Error::MemoryLimitExceeded => "memory_limit_exceeded",
+		Error::Timeout => "timeout",
+		Error::QueueFull => "queue_full",
+		Error::PoolShutDown => "pool_shut_down",
+		Error::Io(_) => "io",
+		Error::System(_) => "system",
+		Error::External(_) => "external",
+		Error::Worker { kind: ErrorKind::InvalidCandidate, .. } => "worker_invalid_candidate",
+		Error::Worker { kind: ErrorKind::Internal, .. } => "worker_internal",
+		Error::UpwardMessageRejected(_) => "upward_message_rejected",
+		Error::Protocol(_) => "protocol",
+		Error::SharedMem(_) => "shared_mem",
+	}
+}
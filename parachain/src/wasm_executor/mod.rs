@@ -20,17 +20,42 @@
 //! Assuming the parameters are correct, this module provides a wrapper around
 //! a WASM VM for re-execution of a parachain candidate.
 
-use std::any::{TypeId, Any};
+use std::{any::{TypeId, Any}, sync::Arc};
 use crate::primitives::{ValidationParams, ValidationResult, UpwardMessage};
 use codec::{Decode, Encode};
+use parking_lot::Mutex;
 use sp_core::storage::ChildInfo;
-use sp_core::traits::CallInWasm;
-use sp_wasm_interface::HostFunctions as _;
-use sp_externalities::Extensions;
-
+use sp_externalities::{Extensions, ExternalitiesExt};
+
+pub use code::{validate_code, CodeError, CodeInfo, VALIDATE_BLOCK};
+pub use executor::{
+	Executor, ExecutorParams, ExecutionMethod, ExecutionStats, HostFunctionPolicy,
+	DEFAULT_CACHE_SIZE, DEFAULT_FUEL_LIMIT, DEFAULT_HEAP_PAGES, DEFAULT_MAX_MEMORY_PAGES,
+};
+pub use trace::{ExecutionTrace, HostCall, LogLine, TraceEvent};
 #[cfg(not(any(target_os = "android", target_os = "unknown")))]
-pub use validation_host::{run_worker, ValidationPool, EXECUTION_TIMEOUT_SEC};
-
+pub use metrics::{Counter, Histogram, MetricsRegistry, ValidationMetrics};
+#[cfg(not(any(target_os = "android", target_os = "unknown")))]
+pub use sandbox::{
+	SandboxConfig, DEFAULT_SANDBOX_MAX_CPU_TIME, DEFAULT_SANDBOX_MAX_MEMORY,
+	DEFAULT_SANDBOX_MAX_OPEN_FILES,
+};
+#[cfg(not(any(target_os = "android", target_os = "unknown")))]
+pub use transport::{Candidate, CustomTransport, HostChannel, Response, Transport, WorkerChannel};
+#[cfg(not(any(target_os = "android", target_os = "unknown")))]
+pub use validation_host::{
+	run_worker, run_worker_with_channel, HostState, Priority, ValidationPool, ValidationPoolConfig,
+	DEFAULT_MAX_QUEUE_DEPTH, DEFAULT_MAX_RESTART_BACKOFF, DEFAULT_NUM_HOSTS,
+	DEFAULT_RESTART_BACKOFF, EXECUTION_TIMEOUT_SEC, WORKER_BINARY,
+};
+
+mod code;
+mod executor;
+mod instrument;
+mod metrics;
+mod sandbox;
+mod trace;
+mod transport;
 mod validation_host;
 
 // maximum memory in bytes
@@ -62,6 +87,11 @@ impl ValidationPool {
 	pub fn new() -> Self {
 		ValidationPool { _inner: () }
 	}
+
+	/// Create a new `ValidationPool` with the given executor parameters.
+	pub fn with_executor_params(_: ExecutorParams) -> Self {
+		ValidationPool { _inner: () }
+	}
 }
 
 /// A stub function defined when compiling for Android or WASM.
@@ -74,12 +104,14 @@ pub fn run_worker(_: &str) -> Result<(), String> {
 ///
 /// > Note: When compiling for WASM, the `Remote` variants are not available.
 pub enum ExecutionMode<'a> {
-	/// Execute in-process. The execution can not be interrupted or aborted.
-	Local,
+	/// Execute in-process with the given executor. The execution can not be interrupted or
+	/// aborted.
+	Local(&'a Executor),
+	/// Execute in-process like `Local`, recording the host function calls and log lines of the
+	/// validation code into the given trace.
+	Traced(&'a Executor, &'a mut ExecutionTrace),
 	/// Remote execution in a spawned process.
 	Remote(&'a ValidationPool),
-	/// Remote execution in a spawned test runner.
-	RemoteTest(&'a ValidationPool),
 }
 
 /// Error type for the wasm executor
@@ -88,33 +120,160 @@ pub enum Error {
 	/// Wasm executor error.
 	#[display(fmt = "WASM executor error: {:?}", _0)]
 	WasmExecutor(sc_executor::error::Error),
+	/// The validation code is invalid.
+	#[display(fmt = "{}", _0)]
+	InvalidCode(CodeError),
+	/// The validation code imports host functions which aren't provided, and the host function
+	/// policy is `Deny`.
+	#[display(fmt = "Validation code imports missing host functions: {:?}", _0)]
+	MissingHostFunctions(Vec<String>),
+	/// The validation code could not be prepared for execution.
+	#[display(fmt = "WASM module error: {:?}", _0)]
+	WasmModule(sc_executor::error::WasmError),
 	/// Call data is too large.
-	#[display(fmt = "Validation parameters are {} bytes, max allowed is {}", _0, MAX_RUNTIME_MEM)]
+	#[display(fmt = "Validation parameters are {} bytes, max allowed is {}", size, max)]
 	#[from(ignore)]
-	ParamsTooLarge(usize),
+	ParamsTooLarge {
+		size: usize,
+		max: usize,
+	},
 	/// Code size it too large.
-	#[display(fmt = "WASM code is {} bytes, max allowed is {}", _0, MAX_CODE_MEM)]
-	CodeTooLarge(usize),
+	#[display(fmt = "WASM code is {} bytes, max allowed is {}", size, max)]
+	#[from(ignore)]
+	CodeTooLarge {
+		size: usize,
+		max: usize,
+	},
+	/// The encoded upward messages of the candidate are too large.
+	#[display(fmt = "Upward messages are {} bytes, max allowed is {}", size, max)]
+	#[from(ignore)]
+	MessagesTooLarge {
+		size: usize,
+		max: usize,
+	},
 	/// Bad return data or type.
 	#[display(fmt = "Validation function returned invalid data.")]
 	BadReturn,
+	/// The validation function used more than the fuel limit.
+	#[display(fmt = "Validation function ran out of fuel.")]
+	OutOfFuel,
+	/// The validation function used more memory than the limit.
+	#[display(fmt = "Validation function exceeded the memory limit.")]
+	MemoryLimitExceeded,
 	#[display(fmt = "Validation function timeout.")]
 	Timeout,
+	/// Too many candidates are waiting for a validation host.
+	#[display(fmt = "Validation queue is full.")]
+	QueueFull,
+	/// The validation pool was shut down.
+	#[display(fmt = "Validation pool is shut down.")]
+	PoolShutDown,
 	#[display(fmt = "IO error: {}", _0)]
 	Io(std::io::Error),
 	#[display(fmt = "System error: {}", _0)]
 	System(Box<dyn std::error::Error + Send>),
 	#[display(fmt = "WASM worker error: {}", _0)]
 	External(String),
+	/// Validation failed in a worker. The worker tells whether the candidate is at fault.
+	#[display(fmt = "WASM worker error: {}", message)]
+	#[from(ignore)]
+	Worker {
+		kind: ErrorKind,
+		message: String,
+	},
+	/// The externalities rejected an upward message posted by the candidate.
+	#[display(fmt = "Upward message rejected: {}", _0)]
+	#[from(ignore)]
+	UpwardMessageRejected(String),
+	/// A validation worker didn't follow the protocol, e.g. because it runs another version.
+	#[display(fmt = "WASM worker protocol error: {}", _0)]
+	#[from(ignore)]
+	Protocol(String),
 	#[display(fmt = "Shared memory error: {}", _0)]
 	#[cfg(not(any(target_os = "android", target_os = "unknown")))]
 	SharedMem(shared_memory::SharedMemError),
 }
 
+/// Whose fault an `Error` is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode)]
+pub enum ErrorKind {
+	/// The candidate is invalid: every validator fails to validate it the same way.
+	InvalidCandidate,
+	/// The validator failed to validate the candidate, which may well be valid. The candidate
+	/// must not be rejected for this, validation can be retried.
+	Internal,
+}
+
+impl Error {
+	/// Whether the candidate or the validator is at fault.
+	///
+	/// Exceeding the code size, message size, fuel and memory limits is the candidate's fault:
+	/// like the rest of the validation rules, these limits must be the same on every validator.
+	/// Exceeding the size of the parameters a validator has room for is internal.
+	pub fn kind(&self) -> ErrorKind {
+		match self {
+			Error::WasmExecutor(e) => executor_error_kind(e),
+			Error::WasmModule(e) => wasm_error_kind(e),
+			Error::InvalidCode(_) |
+			Error::MissingHostFunctions(_) |
+			Error::BadReturn |
+			Error::CodeTooLarge { .. } |
+			Error::MessagesTooLarge { .. } |
+			Error::OutOfFuel |
+			Error::MemoryLimitExceeded |
+			Error::UpwardMessageRejected(_) => ErrorKind::InvalidCandidate,
+			Error::Worker { kind, .. } => *kind,
+			Error::ParamsTooLarge { .. } |
+			Error::Timeout |
+			Error::QueueFull |
+			Error::PoolShutDown |
+			Error::Io(_) |
+			Error::System(_) |
+			Error::External(_) |
+			Error::Protocol(_) => ErrorKind::Internal,
+			#[cfg(not(any(target_os = "android", target_os = "unknown")))]
+			Error::SharedMem(_) => ErrorKind::Internal,
+		}
+	}
+}
+
+/// Whose fault an error of the executor is: the code trapping or misbehaving is the candidate's
+/// fault, failures of the host, like a panic or failing to allocate memory, are internal.
+fn executor_error_kind(error: &sc_executor::error::Error) -> ErrorKind {
+	use sc_executor::error::Error;
+
+	match error {
+		Error::Trap(_) |
+		Error::Wasmi(_) |
+		Error::MethodNotFound(_) |
+		Error::InvalidCode(_) |
+		Error::InvalidIndex |
+		Error::InvalidReturn |
+		Error::InvalidMemoryReference |
+		Error::HeapBaseNotFoundOrInvalid => ErrorKind::InvalidCandidate,
+		_ => ErrorKind::Internal,
+	}
+}
+
+/// Whose fault an error preparing the code is: the code not being a valid module is the
+/// candidate's fault, failing to instantiate it is internal.
+fn wasm_error_kind(error: &sc_executor::error::WasmError) -> ErrorKind {
+	use sc_executor::error::WasmError;
+
+	match error {
+		WasmError::InvalidModule |
+		WasmError::CantDeserializeWasm |
+		WasmError::InvalidMemory |
+		WasmError::Other(_) => ErrorKind::InvalidCandidate,
+		_ => ErrorKind::Internal,
+	}
+}
+
 impl std::error::Error for Error {
 	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
 		match self {
 			Error::WasmExecutor(ref err) => Some(err),
+			Error::InvalidCode(ref err) => Some(err),
 			Error::Io(ref err) => Some(err),
 			Error::System(ref err) => Some(&**err),
 			#[cfg(not(any(target_os = "android", target_os = "unknown")))]
@@ -127,9 +286,23 @@ impl std::error::Error for Error {
 /// Externalities for parachain validation.
 pub trait Externalities: Send {
 	/// Called when a message is to be posted to the parachain's relay chain.
+	///
+	/// The messages of a candidate are posted once it is validated successfully, in every
+	/// execution mode.
 	fn post_upward_message(&mut self, message: UpwardMessage) -> Result<(), String>;
 }
 
+/// The result of validating a candidate, with statistics of the execution.
+#[derive(Debug, Encode, Decode)]
+pub struct ValidationOutcome {
+	/// The result returned by the validation code.
+	pub result: ValidationResult,
+	/// Statistics of the execution of the validation code.
+	///
+	/// With remote execution, the wall time doesn't include the communication with the worker.
+	pub stats: ExecutionStats,
+}
+
 /// Validate a candidate under the given validation code.
 ///
 /// This will fail if the validation code is not a proper parachain validation module.
@@ -139,68 +312,142 @@ pub fn validate_candidate<E: Externalities + 'static>(
 	ext: E,
 	options: ExecutionMode<'_>,
 ) -> Result<ValidationResult, Error> {
+	validate_candidate_with_stats(validation_code, params, ext, options)
+		.map(|outcome| outcome.result)
+}
+
+/// Validate a candidate under the given validation code, returning statistics of the execution
+/// along with the result.
+///
+/// This will fail if the validation code is not a proper parachain validation module.
+pub fn validate_candidate_with_stats<E: Externalities + 'static>(
+	validation_code: &[u8],
+	params: ValidationParams,
+	ext: E,
+	options: ExecutionMode<'_>,
+) -> Result<ValidationOutcome, Error> {
 	match options {
-		ExecutionMode::Local => {
-			validate_candidate_internal(validation_code, &params.encode(), ext)
+		ExecutionMode::Local(executor) => {
+			validate_candidate_internal(executor, validation_code, &params.encode(), ext)
 		},
-		#[cfg(not(any(target_os = "android", target_os = "unknown")))]
-		ExecutionMode::Remote(pool) => {
-			pool.validate_candidate(validation_code, params, ext, false)
+		ExecutionMode::Traced(executor, trace) => {
+			execute(executor, validation_code, &params.encode(), ext, Some(trace))
 		},
 		#[cfg(not(any(target_os = "android", target_os = "unknown")))]
-		ExecutionMode::RemoteTest(pool) => {
-			pool.validate_candidate(validation_code, params, ext, true)
+		ExecutionMode::Remote(pool) => {
+			pool.validate_candidate(validation_code, params, ext)
 		},
 		#[cfg(any(target_os = "android", target_os = "unknown"))]
 		ExecutionMode::Remote(pool) =>
 			Err(Error::System(Box::<dyn std::error::Error + Send + Sync>::from(
 				"Remote validator not available".to_string()
 			) as Box<_>)),
+	}
+}
+
+/// Validate candidates under the same validation code, returning their results in the order
+/// of the candidates.
+///
+/// The code is prepared once, unless the executor doesn't cache it. With `Remote`, the candidates
+/// are spread across the hosts of the pool. With `Traced`, the trace is of the last candidate.
+pub fn validate_candidates<E: Externalities + 'static>(
+	validation_code: &[u8],
+	candidates: Vec<(ValidationParams, E)>,
+	options: ExecutionMode<'_>,
+) -> Vec<Result<ValidationOutcome, Error>> {
+	match options {
+		ExecutionMode::Local(executor) => candidates.into_iter()
+			.map(|(params, ext)| {
+				validate_candidate_internal(executor, validation_code, &params.encode(), ext)
+			})
+			.collect(),
+		ExecutionMode::Traced(executor, trace) => candidates.into_iter()
+			.map(|(params, ext)| {
+				execute(executor, validation_code, &params.encode(), ext, Some(&mut *trace))
+			})
+			.collect(),
+		#[cfg(not(any(target_os = "android", target_os = "unknown")))]
+		ExecutionMode::Remote(pool) => pool.validate_candidates(validation_code, candidates),
 		#[cfg(any(target_os = "android", target_os = "unknown"))]
-		ExecutionMode::RemoteTest(pool) =>
-			Err(Error::System(Box::<dyn std::error::Error + Send + Sync>::from(
+		ExecutionMode::Remote(_) => candidates.iter()
+			.map(|_| Err(Error::System(Box::<dyn std::error::Error + Send + Sync>::from(
 				"Remote validator not available".to_string()
-			) as Box<_>)),
+			) as Box<_>)))
+			.collect(),
 	}
 }
 
 /// The host functions provided by the wasm executor to the parachain wasm blob.
-type HostFunctions = (
+pub(crate) type HostFunctions = (
 	sp_io::SubstrateHostFunctions,
 	crate::wasm_api::parachain::HostFunctions,
 );
 
-/// Validate a candidate under the given validation code.
+/// Validate a candidate under the given validation code, using `executor`.
 ///
 /// This will fail if the validation code is not a proper parachain validation module.
 pub fn validate_candidate_internal<E: Externalities + 'static>(
+	executor: &Executor,
 	validation_code: &[u8],
 	encoded_call_data: &[u8],
 	externalities: E,
-) -> Result<ValidationResult, Error> {
+) -> Result<ValidationOutcome, Error> {
+	execute(executor, validation_code, encoded_call_data, externalities, None)
+}
+
+/// Like `validate_candidate_internal`, recording the execution into `trace` if given. The trace
+/// is recorded whether or not the execution succeeds.
+fn execute<E: Externalities + 'static>(
+	executor: &Executor,
+	validation_code: &[u8],
+	encoded_call_data: &[u8],
+	mut externalities: E,
+	trace: Option<&mut ExecutionTrace>,
+) -> Result<ValidationOutcome, Error> {
+	let messages = Arc::new(Mutex::new(Vec::new()));
+
 	let mut extensions = Extensions::new();
-	extensions.register(ParachainExt::new(externalities));
+	extensions.register(ParachainExt::new(BufferedExternalities(messages.clone())));
 	extensions.register(sp_core::traits::TaskExecutorExt(sp_core::tasks::executor()));
 
+	if trace.is_some() {
+		extensions.register(trace::TraceExt(trace::TraceRecorder::new()));
+	}
+
 	let mut ext = ValidationExternalities(extensions);
 
-	let executor = sc_executor::WasmExecutor::new(
-		sc_executor::WasmExecutionMethod::Interpreted,
-		// TODO: Make sure we don't use more than 1GB: https://github.com/paritytech/polkadot/issues/699
-		Some(1024),
-		HostFunctions::host_functions(),
-		8
-	);
-	let res = executor.call_in_wasm(
-		validation_code,
-		None,
-		"validate_block",
-		encoded_call_data,
-		&mut ext,
-		sp_core::traits::MissingHostFunctions::Allow,
-	)?;
-
-	ValidationResult::decode(&mut &res[..]).map_err(|_| Error::BadReturn.into())
+	let start = std::time::Instant::now();
+	let res = executor.call(validation_code, VALIDATE_BLOCK, encoded_call_data, &mut ext);
+	if let Some(trace) = trace {
+		let mut ext: &mut dyn sp_externalities::Externalities = &mut ext;
+		if let Some(recorder) = ext.extension::<trace::TraceExt>() {
+			let recorder = std::mem::replace(&mut recorder.0, trace::TraceRecorder::new());
+			*trace = recorder.finish(start.elapsed());
+		}
+	}
+
+	let (res, mut stats) = res?;
+	let result = ValidationResult::decode(&mut &res[..]).map_err(|_| Error::BadReturn)?;
+
+	let messages = std::mem::replace(&mut *messages.lock(), Vec::new());
+	stats.upward_messages = messages.len() as u32;
+	stats.upward_message_bytes = messages.iter().map(|message| message.data.len() as u64).sum();
+	messages.into_iter()
+		.try_for_each(|message| externalities.post_upward_message(message))
+		.map_err(Error::UpwardMessageRejected)?;
+
+	Ok(ValidationOutcome { result, stats })
+}
+
+/// Externalities keeping the upward messages posted through them, which are only posted to the
+/// externalities of the candidate once it is validated.
+struct BufferedExternalities(Arc<Mutex<Vec<UpwardMessage>>>);
+
+impl Externalities for BufferedExternalities {
+	fn post_upward_message(&mut self, message: UpwardMessage) -> Result<(), String> {
+		self.0.lock().push(message);
+		Ok(())
+	}
 }
 
 /// The validation externalities that will panic on any storage related access. They just provide
@@ -312,3 +559,45 @@ impl sp_externalities::ExtensionStore for ValidationExternalities {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sc_executor::error::{Error as ExecutorError, WasmError};
+
+	#[test]
+	fn errors_of_the_code_are_invalid_candidates() {
+		let errors = vec![
+			Error::from(ExecutorError::InvalidMemoryReference),
+			ExecutorError::MethodNotFound("validate_block".into()).into(),
+			ExecutorError::InvalidReturn.into(),
+			WasmError::InvalidModule.into(),
+			Error::BadReturn,
+			Error::CodeTooLarge { size: 2, max: 1 },
+			Error::MessagesTooLarge { size: 2, max: 1 },
+			Error::OutOfFuel,
+			Error::MemoryLimitExceeded,
+			Error::UpwardMessageRejected("Too many messages".into()),
+			Error::Worker { kind: ErrorKind::InvalidCandidate, message: String::new() },
+		];
+		for error in errors {
+			assert_eq!(error.kind(), ErrorKind::InvalidCandidate, "{}", error);
+		}
+	}
+
+	#[test]
+	fn errors_of_the_validator_are_internal() {
+		let errors = vec![
+			Error::from(ExecutorError::RuntimePanicked("unsupported externalities".into())),
+			ExecutorError::Other("out of memory".into()).into(),
+			WasmError::Instantiation("out of memory".into()).into(),
+			Error::ParamsTooLarge { size: 2, max: 1 },
+			Error::Timeout,
+			Error::Protocol("Unexpected response".into()),
+			Error::Worker { kind: ErrorKind::Internal, message: String::new() },
+		];
+		for error in errors {
+			assert_eq!(error.kind(), ErrorKind::Internal, "{}", error);
+		}
+	}
+}
@@ -0,0 +1,287 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Sandboxing of validation workers.
+//!
+//! A sandboxed worker drops its privileges before it validates its first candidate: resource
+//! limits bound its memory, CPU time and file descriptors, and a seccomp filter makes every
+//! system call the executor doesn't need fail with `EPERM`. This rules out opening files and
+//! sockets, starting processes, and raising the limits again, while threads can still be
+//! started. Only Linux on x86-64 and AArch64 is supported.
+
+#![cfg(not(any(target_os = "android", target_os = "unknown")))]
+
+use std::time::Duration;
+use codec::{Decode, Encode};
+
+/// Memory a sandboxed worker can allocate by default: 2 GiB.
+pub const DEFAULT_SANDBOX_MAX_MEMORY: u64 = 2 * 1024 * 1024 * 1024;
+
+/// CPU time a sandboxed worker can use by default.
+pub const DEFAULT_SANDBOX_MAX_CPU_TIME: Duration = Duration::from_secs(60);
+
+/// File descriptors a sandboxed worker can have open by default.
+pub const DEFAULT_SANDBOX_MAX_OPEN_FILES: u64 = 32;
+
+/// Limits of a sandboxed validation worker.
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct SandboxConfig {
+	/// Private memory the worker can allocate, in bytes. The shared memory of
+	/// `Transport::SharedMemory` doesn't count against this.
+	pub max_memory: u64,
+	/// CPU time the worker can use once sandboxed, rounded up to whole seconds. The worker is
+	/// killed by the system when it uses more, so hosts replace their worker once it is idle
+	/// and has less left than the execution timeout.
+	pub max_cpu_time: Duration,
+	/// Number of file descriptors the worker can have open.
+	pub max_open_files: u64,
+}
+
+impl Default for SandboxConfig {
+	fn default() -> Self {
+		SandboxConfig {
+			max_memory: DEFAULT_SANDBOX_MAX_MEMORY,
+			max_cpu_time: DEFAULT_SANDBOX_MAX_CPU_TIME,
+			max_open_files: DEFAULT_SANDBOX_MAX_OPEN_FILES,
+		}
+	}
+}
+
+/// Sandbox the current process with `config`, for all its threads.
+#[cfg(target_os = "linux")]
+pub(crate) fn enter(config: &SandboxConfig) -> Result<(), String> {
+	let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+	if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+		return Err(format!("Can't get the CPU time: {}", std::io::Error::last_os_error()));
+	}
+	let used = (usage.ru_utime.tv_sec + usage.ru_stime.tv_sec) as u64 + 1;
+	let allowed = config.max_cpu_time.as_secs() + (config.max_cpu_time.subsec_nanos() > 0) as u64;
+
+	set_limit("memory", libc::RLIMIT_DATA, config.max_memory)?;
+	set_limit("open files", libc::RLIMIT_NOFILE, config.max_open_files)?;
+	set_limit("core size", libc::RLIMIT_CORE, 0)?;
+	set_limit("CPU time", libc::RLIMIT_CPU, used + allowed)?;
+	seccomp::install()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enter(_: &SandboxConfig) -> Result<(), String> {
+	Err("Sandboxing is only supported on Linux".into())
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(all(target_os = "linux", not(target_env = "gnu")))]
+type Resource = libc::c_int;
+
+/// Set both the soft and hard limit of `resource` to `value`.
+#[cfg(target_os = "linux")]
+fn set_limit(name: &str, resource: Resource, value: u64) -> Result<(), String> {
+	let limit = libc::rlimit { rlim_cur: value as libc::rlim_t, rlim_max: value as libc::rlim_t };
+	if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+		return Err(format!("Can't limit the {}: {}", name, std::io::Error::last_os_error()));
+	}
+	Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod seccomp {
+	use libc::{c_long, sock_filter, sock_fprog};
+
+	// Kernel ABI constants, from `linux/bpf_common.h`, `linux/seccomp.h` and `linux/audit.h`.
+	/// `BPF_LD | BPF_W | BPF_ABS`
+	const BPF_LD_W_ABS: u16 = 0x20;
+	/// `BPF_JMP | BPF_JEQ | BPF_K`
+	const BPF_JMP_JEQ_K: u16 = 0x15;
+	/// `BPF_JMP | BPF_JSET | BPF_K`
+	const BPF_JMP_JSET_K: u16 = 0x45;
+	/// `BPF_RET | BPF_K`
+	const BPF_RET_K: u16 = 0x06;
+	const SECCOMP_SET_MODE_FILTER: libc::c_uint = 1;
+	const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+	const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+	const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+	const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+	/// Offsets of the fields of `struct seccomp_data`.
+	const SYSCALL_NR_OFFSET: u32 = 0;
+	const ARCH_OFFSET: u32 = 4;
+	/// The low half of the first argument, on little-endian architectures.
+	const FIRST_ARG_OFFSET: u32 = 16;
+	/// `clone3`, which has the same number on every architecture.
+	const SYS_CLONE3: c_long = 435;
+
+	#[cfg(target_arch = "x86_64")]
+	const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+	#[cfg(target_arch = "aarch64")]
+	const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+	#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+	const AUDIT_ARCH: Option<u32> = None;
+
+	/// System calls made by workers after they are sandboxed: memory management, waiting on
+	/// the transport, reading and writing already open descriptors, time, signals and exiting.
+	const ALLOWED_SYSCALLS: &[c_long] = &[
+		libc::SYS_read,
+		libc::SYS_write,
+		libc::SYS_writev,
+		libc::SYS_close,
+		libc::SYS_futex,
+		libc::SYS_mmap,
+		libc::SYS_munmap,
+		libc::SYS_mremap,
+		libc::SYS_mprotect,
+		libc::SYS_madvise,
+		libc::SYS_brk,
+		libc::SYS_rt_sigaction,
+		libc::SYS_rt_sigprocmask,
+		libc::SYS_rt_sigreturn,
+		libc::SYS_sigaltstack,
+		libc::SYS_clock_gettime,
+		libc::SYS_clock_nanosleep,
+		libc::SYS_nanosleep,
+		libc::SYS_sched_yield,
+		libc::SYS_getpid,
+		libc::SYS_gettid,
+		libc::SYS_getrandom,
+		// Registered by new threads.
+		libc::SYS_set_robust_list,
+		libc::SYS_rseq,
+		libc::SYS_exit,
+		libc::SYS_exit_group,
+	];
+
+	fn statement(code: u16, k: u32) -> sock_filter {
+		sock_filter { code, jt: 0, jf: 0, k }
+	}
+
+	fn jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+		sock_filter { code, jt, jf, k }
+	}
+
+	/// Install the filter for all threads of the process. The calling process can't regain the
+	/// privileges, nor can the processes it starts.
+	pub(super) fn install() -> Result<(), String> {
+		let arch = AUDIT_ARCH
+			.ok_or_else(|| "Sandboxing is not supported on this architecture".to_string())?;
+
+		let mut filter = vec![
+			statement(BPF_LD_W_ABS, ARCH_OFFSET),
+			jump(BPF_JMP_JEQ_K, arch, 1, 0),
+			statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+			statement(BPF_LD_W_ABS, SYSCALL_NR_OFFSET),
+		];
+		for syscall in ALLOWED_SYSCALLS {
+			filter.push(jump(BPF_JMP_JEQ_K, *syscall as u32, 0, 1));
+			filter.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+		}
+		// The flags of `clone3` can't be checked, as they are passed in memory. Pretending it
+		// doesn't exist makes the C library fall back to `clone`.
+		filter.push(jump(BPF_JMP_JEQ_K, SYS_CLONE3 as u32, 0, 1));
+		filter.push(statement(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+		// `clone` only starts threads, not processes.
+		filter.push(jump(BPF_JMP_JEQ_K, libc::SYS_clone as u32, 0, 3));
+		filter.push(statement(BPF_LD_W_ABS, FIRST_ARG_OFFSET));
+		filter.push(jump(BPF_JMP_JSET_K, libc::CLONE_THREAD as u32, 0, 1));
+		filter.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+		filter.push(statement(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+
+		let program = sock_fprog {
+			len: filter.len() as libc::c_ushort,
+			filter: filter.as_mut_ptr(),
+		};
+		unsafe {
+			let (one, zero): (libc::c_ulong, libc::c_ulong) = (1, 0);
+			if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, one, zero, zero, zero) != 0 {
+				return Err(format!(
+					"Can't drop privileges: {}",
+					std::io::Error::last_os_error(),
+				));
+			}
+			if libc::syscall(
+				libc::SYS_seccomp,
+				SECCOMP_SET_MODE_FILTER,
+				SECCOMP_FILTER_FLAG_TSYNC,
+				&program as *const sock_fprog,
+			) != 0 {
+				return Err(format!(
+					"Can't install the seccomp filter: {}",
+					std::io::Error::last_os_error(),
+				));
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(all(test, target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+	use super::*;
+	use std::{env, io, process::Command};
+
+	/// Set when the test binary is run again to enter the sandbox, which can't be left.
+	const SANDBOXED: &str = "POWERPLAY_SANDBOX_TEST";
+
+	fn last_error() -> Option<i32> {
+		io::Error::last_os_error().raw_os_error()
+	}
+
+	/// Whether this is the process the test `name` is run again in, which enters the sandbox.
+	/// Otherwise runs it, and checks it passes.
+	fn sandboxed(name: &str) -> bool {
+		if env::var_os(SANDBOXED).is_some() {
+			enter(&SandboxConfig::default()).unwrap();
+			return true;
+		}
+
+		let status = Command::new(env::current_exe().unwrap())
+			.args(&["--exact", "--test-threads=1"])
+			.arg(format!("wasm_executor::sandbox::tests::{}", name))
+			.env(SANDBOXED, "1")
+			.status()
+			.unwrap();
+		assert!(status.success());
+		false
+	}
+
+	#[test]
+	fn sandboxed_workers_cant_open_files_or_sockets() {
+		if !sandboxed("sandboxed_workers_cant_open_files_or_sockets") {
+			return;
+		}
+
+		let path = b"/dev/null\0";
+		let file = unsafe { libc::open(path.as_ptr() as *const libc::c_char, libc::O_RDONLY) };
+		assert_eq!((file, last_error()), (-1, Some(libc::EPERM)));
+
+		let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+		assert_eq!((socket, last_error()), (-1, Some(libc::EPERM)));
+
+		let limit = libc::rlimit { rlim_cur: libc::RLIM_INFINITY, rlim_max: libc::RLIM_INFINITY };
+		let result = unsafe { libc::setrlimit(libc::RLIMIT_CPU, &limit) };
+		assert_eq!((result, last_error()), (-1, Some(libc::EPERM)));
+	}
+
+	#[test]
+	fn sandboxed_workers_can_start_threads_but_not_processes() {
+		if !sandboxed("sandboxed_workers_can_start_threads_but_not_processes") {
+			return;
+		}
+
+		assert_eq!(std::thread::spawn(|| 1 + 1).join().unwrap(), 2);
+
+		let pid = unsafe { libc::fork() };
+		assert_eq!((pid, last_error()), (-1, Some(libc::EPERM)));
+	}
+}
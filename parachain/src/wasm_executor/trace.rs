@@ -0,0 +1,313 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Traces of the host function calls made by validation code.
+//!
+//! A trace is recorded when validating with `ExecutionMode::Traced`, and can be written out as
+//! JSON, or as folded stacks for flamegraph tools.
+
+use std::{fmt::Write, time::{Duration, Instant}};
+#[cfg(feature = "std")]
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use sp_wasm_interface::Value;
+use super::VALIDATE_BLOCK;
+
+/// Name of the `sp_io` host function emitting log lines.
+pub(crate) const LOG_FUNCTION: &str = "ext_logging_log_version_1";
+
+sp_externalities::decl_extension! {
+	/// The extension collecting the trace of an execution.
+	pub(crate) struct TraceExt(TraceRecorder);
+}
+
+/// Collects a trace while validation code executes.
+pub(crate) struct TraceRecorder {
+	started: Instant,
+	events: Vec<TraceEvent>,
+}
+
+impl TraceRecorder {
+	pub(crate) fn new() -> Self {
+		TraceRecorder { started: Instant::now(), events: Vec::new() }
+	}
+
+	/// Time since the start of the execution.
+	pub(crate) fn elapsed(&self) -> Duration {
+		self.started.elapsed()
+	}
+
+	pub(crate) fn push(&mut self, event: TraceEvent) {
+		self.events.push(event);
+	}
+
+	/// Finish the trace of an execution which took `duration`.
+	pub(crate) fn finish(self, duration: Duration) -> ExecutionTrace {
+		ExecutionTrace { events: self.events, duration }
+	}
+}
+
+/// A call of a host function by validation code.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "std", derive(Serialize))]
+pub struct HostCall {
+	/// Name of the host function.
+	pub name: String,
+	/// The arguments passed by the code.
+	#[cfg_attr(feature = "std", serde(serialize_with = "serialize_values"))]
+	pub args: Vec<Value>,
+	/// The value returned to the code, or the error trapping it.
+	#[cfg_attr(feature = "std", serde(flatten, serialize_with = "serialize_result"))]
+	pub result: Result<Option<Value>, String>,
+	/// Number of bytes the host function read from the memory of the code.
+	pub bytes_read: u64,
+	/// Number of bytes the host function wrote to the memory of the code.
+	pub bytes_written: u64,
+	/// When the call started, since the start of the execution.
+	#[cfg_attr(feature = "std", serde(rename = "start_ns", serialize_with = "serialize_nanos"))]
+	pub start: Duration,
+	/// How long the call took.
+	#[cfg_attr(feature = "std", serde(rename = "duration_ns", serialize_with = "serialize_nanos"))]
+	pub duration: Duration,
+}
+
+/// A line logged by validation code through `sp_io::logging`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "std", derive(Serialize))]
+pub struct LogLine {
+	/// The log level, 1 being `Error` and 5 being `Trace`.
+	pub level: u32,
+	/// The log target, truncated to 4096 bytes.
+	pub target: String,
+	/// The message, truncated to 4096 bytes and lossily decoded as UTF-8.
+	pub message: String,
+	/// When the line was logged, since the start of the execution.
+	#[cfg_attr(feature = "std", serde(rename = "time_ns", serialize_with = "serialize_nanos"))]
+	pub time: Duration,
+}
+
+/// Something that happened during an execution of validation code.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "std", derive(Serialize))]
+#[cfg_attr(feature = "std", serde(tag = "type", rename_all = "snake_case"))]
+pub enum TraceEvent {
+	/// A host function was called.
+	HostCall(HostCall),
+	/// A line was logged.
+	Log(LogLine),
+}
+
+/// The trace of an execution of validation code.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "std", derive(Serialize))]
+pub struct ExecutionTrace {
+	/// What happened, in order.
+	pub events: Vec<TraceEvent>,
+	/// How long the execution took, including preparing the code if it wasn't cached.
+	#[cfg_attr(feature = "std", serde(rename = "duration_ns", serialize_with = "serialize_nanos"))]
+	pub duration: Duration,
+}
+
+impl ExecutionTrace {
+	/// The host function calls, in order.
+	pub fn host_calls(&self) -> impl Iterator<Item = &HostCall> {
+		self.events.iter().filter_map(|event| match event {
+			TraceEvent::HostCall(call) => Some(call),
+			TraceEvent::Log(_) => None,
+		})
+	}
+
+	/// The logged lines, in order.
+	pub fn log_lines(&self) -> impl Iterator<Item = &LogLine> {
+		self.events.iter().filter_map(|event| match event {
+			TraceEvent::Log(line) => Some(line),
+			TraceEvent::HostCall(_) => None,
+		})
+	}
+
+	/// The trace as a JSON object, with times in nanoseconds.
+	#[cfg(feature = "std")]
+	pub fn to_json(&self) -> String {
+		serde_json::to_string(self).expect("traces only contain serializable values; qed")
+	}
+
+	/// The trace as folded stacks weighted by nanoseconds, as taken by `flamegraph.pl` and
+	/// `inferno-flamegraph`.
+	///
+	/// Host function calls are stacked on `validate_block`, whose own weight is the time spent
+	/// outside of host functions.
+	pub fn to_folded(&self) -> String {
+		let mut totals: Vec<(&str, u128)> = Vec::new();
+		for call in self.host_calls() {
+			match totals.iter_mut().find(|(name, _)| *name == call.name) {
+				Some((_, total)) => *total += call.duration.as_nanos(),
+				None => totals.push((&call.name, call.duration.as_nanos())),
+			}
+		}
+
+		let host_time: u128 = totals.iter().map(|(_, total)| total).sum();
+		let mut folded = String::new();
+		let _ = writeln!(
+			folded,
+			"{} {}",
+			VALIDATE_BLOCK,
+			self.duration.as_nanos().saturating_sub(host_time),
+		);
+		for (name, total) in totals {
+			let _ = writeln!(folded, "{};{} {}", VALIDATE_BLOCK, name, total);
+		}
+		folded
+	}
+}
+
+#[cfg(feature = "std")]
+fn serialize_nanos<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+	serializer.serialize_u64(duration.as_nanos() as u64)
+}
+
+#[cfg(feature = "std")]
+fn serialize_values<S: Serializer>(values: &[Value], serializer: S) -> Result<S::Ok, S::Error> {
+	serializer.collect_seq(values.iter().map(SerializeValue))
+}
+
+/// The result of a host call as a `result` entry, or an `error` entry if it trapped.
+#[cfg(feature = "std")]
+fn serialize_result<S: Serializer>(
+	result: &Result<Option<Value>, String>,
+	serializer: S,
+) -> Result<S::Ok, S::Error> {
+	let mut map = serializer.serialize_map(Some(1))?;
+	match result {
+		Ok(value) => map.serialize_entry("result", &value.as_ref().map(SerializeValue))?,
+		Err(error) => map.serialize_entry("error", error)?,
+	}
+	map.end()
+}
+
+/// A wasm value as a number. JSON has no NaN or infinities, which are written as `null`.
+#[cfg(feature = "std")]
+struct SerializeValue<'a>(&'a Value);
+
+#[cfg(feature = "std")]
+impl Serialize for SerializeValue<'_> {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		match *self.0 {
+			Value::I32(value) => serializer.serialize_i32(value),
+			Value::I64(value) => serializer.serialize_i64(value),
+			Value::F32(bits) => match f32::from_bits(bits) {
+				value if value.is_finite() => serializer.serialize_f32(value),
+				_ => serializer.serialize_none(),
+			},
+			Value::F64(bits) => match f64::from_bits(bits) {
+				value if value.is_finite() => serializer.serialize_f64(value),
+				_ => serializer.serialize_none(),
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn trace() -> ExecutionTrace {
+		let call = |name: &str, args, result, start, duration| TraceEvent::HostCall(HostCall {
+			name: name.into(),
+			args,
+			result,
+			bytes_read: 4,
+			bytes_written: 8,
+			start: Duration::from_nanos(start),
+			duration: Duration::from_nanos(duration),
+		});
+
+		ExecutionTrace {
+			events: vec![
+				call(
+					"ext_a",
+					vec![Value::I32(-1), Value::I64(2)],
+					Ok(Some(Value::I32(3))),
+					10,
+					100,
+				),
+				TraceEvent::Log(LogLine {
+					level: 2,
+					target: "runtime".into(),
+					message: "\"quoted\"\n\u{1}".into(),
+					time: Duration::from_nanos(150),
+				}),
+				call("ext_b", vec![Value::F32(f32::NAN.to_bits())], Ok(None), 200, 50),
+				call("ext_a", vec![Value::F64(0.5f64.to_bits())], Err("trap".into()), 300, 20),
+			],
+			duration: Duration::from_nanos(1000),
+		}
+	}
+
+	#[test]
+	fn trace_is_written_as_json() {
+		let json: serde_json::Value = serde_json::from_str(&trace().to_json()).unwrap();
+
+		assert_eq!(json, serde_json::json!({
+			"duration_ns": 1000,
+			"events": [
+				{
+					"type": "host_call",
+					"name": "ext_a",
+					"args": [-1, 2],
+					"result": 3,
+					"bytes_read": 4,
+					"bytes_written": 8,
+					"start_ns": 10,
+					"duration_ns": 100,
+				},
+				{
+					"type": "log",
+					"level": 2,
+					"target": "runtime",
+					"message": "\"quoted\"\n\u{1}",
+					"time_ns": 150,
+				},
+				{
+					"type": "host_call",
+					"name": "ext_b",
+					"args": [null],
+					"result": null,
+					"bytes_read": 4,
+					"bytes_written": 8,
+					"start_ns": 200,
+					"duration_ns": 50,
+				},
+				{
+					"type": "host_call",
+					"name": "ext_a",
+					"args": [0.5],
+					"error": "trap",
+					"bytes_read": 4,
+					"bytes_written": 8,
+					"start_ns": 300,
+					"duration_ns": 20,
+				},
+			],
+		}));
+	}
+
+	#[test]
+	fn trace_is_written_as_folded_stacks() {
+		assert_eq!(
+			trace().to_folded(),
+			"validate_block 830\nvalidate_block;ext_a 120\nvalidate_block;ext_b 50\n",
+		);
+	}
+}
@@ -0,0 +1,779 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Transports carrying candidates and results between validation hosts and their workers.
+//!
+//! With `Transport::SharedMemory`, the host allocates shared memory large enough for the largest
+//! candidate, and both sides signal each other with events. With `Transport::Stdio`, candidates
+//! and results are sent as frames over the standard input and output of the worker, so only the
+//! data of the current candidate is held in memory.
+//!
+//! In both cases the worker starts by sending a handshake, and every frame is prefixed with its
+//! length and checksum.
+//!
+//! Other transports, like sockets, are implemented outside of this crate with `CustomTransport`
+//! and the `HostChannel` and `WorkerChannel` ends it connects.
+
+#![cfg(not(any(target_os = "android", target_os = "unknown")))]
+
+use std::{
+	fmt, io::{self, Read, Write}, process, sync::{atomic, mpsc, Arc}, thread, time::Duration,
+};
+use codec::{Decode, Encode};
+use shared_memory::{SharedMem, SharedMemConf, EventState, WriteLockable, EventWait, EventSet};
+use sp_core::hashing::blake2_256;
+use log::{debug, trace};
+use super::{Error, ErrorKind, ExecutorParams, SandboxConfig, ValidationPoolConfig};
+
+/// Size of the header at the start of the shared memory.
+const HEADER_MEM: usize = 1024;
+
+/// Id passed to a worker instead of the id of a shared memory, to use `Transport::Stdio`.
+pub(crate) const STDIO_WORKER_ID: &str = "stdio";
+
+/// Version of the protocol between hosts and workers. Must be bumped on any change of the
+/// data exchanged, or of the layout of the shared memory.
+///
+/// Only the framing of the handshake must never change.
+const PROTOCOL_VERSION: u32 = 5;
+
+/// Size of the length and checksum prefixing a frame.
+const FRAME_PREFIX: usize = 4 + 32;
+
+/// Longest error message a worker sends in the result header, leaving room for the frame prefix
+/// and the rest of the header.
+const MAX_ERROR_MESSAGE: usize = HEADER_MEM - FRAME_PREFIX - 16;
+
+/// How hosts exchange candidates and results with their workers.
+#[derive(Clone)]
+pub enum Transport {
+	/// Shared memory, sized for the largest candidate and its upward messages. Copies the least
+	/// data, but needs more than 1 GiB of shared memory per host with the default limits.
+	SharedMemory,
+	/// Frames over the standard input and output of the worker. Needs no shared memory, so it
+	/// suits small candidates and environments restricting it, like containers.
+	///
+	/// Nothing else may write to the standard output of the worker.
+	Stdio,
+	/// A transport implemented outside of this crate. Its workers must call
+	/// `run_worker_with_channel` with their end of it.
+	Custom(Arc<dyn CustomTransport>),
+}
+
+impl fmt::Debug for Transport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Transport::SharedMemory => f.write_str("SharedMemory"),
+			Transport::Stdio => f.write_str("Stdio"),
+			Transport::Custom(_) => f.write_str("Custom"),
+		}
+	}
+}
+
+impl Default for Transport {
+	fn default() -> Self {
+		Transport::SharedMemory
+	}
+}
+
+/// A candidate sent by a host to its worker.
+#[derive(Encode, Decode)]
+pub struct Candidate {
+	/// The validation code, or `None` to use the code of the previous candidate again.
+	pub code: Option<Vec<u8>>,
+	/// The encoded `ValidationParams`.
+	pub params: Vec<u8>,
+	/// Parameters of the execution of the code.
+	pub executor_params: ExecutorParams,
+	/// Maximum size of the encoded upward messages of the candidate, in bytes.
+	pub max_message_size: u64,
+	/// The sandbox the worker validates in, if any.
+	pub sandbox: Option<SandboxConfig>,
+}
+
+/// The response of a worker to a candidate.
+#[derive(Encode, Decode, Debug)]
+pub enum Response {
+	/// The candidate was validated, with its encoded `ValidationOutcome` and encoded upward
+	/// messages.
+	Ok {
+		outcome: Vec<u8>,
+		messages: Vec<u8>,
+	},
+	/// Validation failed, with the fault of the error.
+	Error(ErrorKind, String),
+}
+
+/// A transport implemented outside of this crate, see `Transport::Custom`.
+pub trait CustomTransport: Send + Sync {
+	/// Spawn `command` as a worker connected with this transport, and wait until it is ready
+	/// for candidates, at most `config.start_timeout`.
+	///
+	/// The worker must be killed if it fails to start.
+	fn start_worker(
+		&self,
+		command: process::Command,
+		config: &ValidationPoolConfig,
+	) -> Result<(process::Child, Box<dyn HostChannel>), Error>;
+}
+
+/// The host end of a transport.
+pub trait HostChannel: Send {
+	/// Send a candidate to the worker.
+	fn send(&mut self, candidate: &Candidate) -> Result<(), Error>;
+
+	/// Wait for the response of the worker to the last candidate, failing with `Error::Timeout`
+	/// once `timeout` elapsed.
+	fn receive(&mut self, timeout: Duration) -> Result<Response, Error>;
+}
+
+/// The worker end of a transport.
+pub trait WorkerChannel {
+	/// Wait for the next candidate, or `None` once the host is gone.
+	///
+	/// Fails if the host sent an invalid request, which the worker answers with an error.
+	fn receive(&mut self) -> Result<Option<Candidate>, String>;
+
+	/// Send the response to the last candidate.
+	fn send(&mut self, response: Response) -> Result<(), String>;
+}
+
+/// Spawn `command` as a worker connected with `transport`, waiting for its handshake.
+///
+/// The worker is killed if it fails to start.
+pub(crate) fn start_worker(
+	transport: &Transport,
+	mut command: process::Command,
+	config: &ValidationPoolConfig,
+) -> Result<(process::Child, Box<dyn HostChannel>), Error> {
+	let layout = MemoryLayout::new(config);
+	match transport {
+		Transport::SharedMemory => {
+			let mut memory = create_memory(layout)?;
+			let mut worker = command
+				.arg(memory.get_os_path())
+				.stdin(process::Stdio::piped())
+				.spawn()?;
+			let handshake = read_shm_handshake(&mut memory, config.start_timeout);
+			if let Err(e) = handshake.and_then(check_handshake) {
+				worker.kill().ok();
+				return Err(e);
+			}
+			Ok((worker, Box::new(ShmHost { memory, layout })))
+		},
+		Transport::Stdio => {
+			let mut worker = command
+				.arg(STDIO_WORKER_ID)
+				.stdin(process::Stdio::piped())
+				.stdout(process::Stdio::piped())
+				.spawn()?;
+			let (stdin, stdout) = match (worker.stdin.take(), worker.stdout.take()) {
+				(Some(stdin), Some(stdout)) => (stdin, stdout),
+				_ => {
+					worker.kill().ok();
+					return Err(Error::External("Worker pipes are missing".into()));
+				},
+			};
+			let mut host = StdioHost { stdin, frames: read_frames(stdout, layout.size()) };
+			let handshake = host.receive_frame(config.start_timeout)
+				.and_then(|frame| decode_payload(&frame).map_err(Error::Protocol));
+			if let Err(e) = handshake.and_then(check_handshake) {
+				worker.kill().ok();
+				return Err(e);
+			}
+			Ok((worker, Box::new(host)))
+		},
+		Transport::Custom(transport) => transport.start_worker(command, config),
+	}
+}
+
+/// Open the worker end of the transport the host passed `id` for: the id of the shared memory,
+/// or `STDIO_WORKER_ID`.
+pub(crate) fn open_worker(id: &str) -> Result<Box<dyn WorkerChannel>, String> {
+	if id == STDIO_WORKER_ID {
+		let mut stdout = io::stdout();
+		write_stream_frame(&mut stdout, &Handshake { version: PROTOCOL_VERSION })
+			.map_err(|e| format!("Error writing the handshake: {}", e))?;
+		return Ok(Box::new(StdioWorker { stdin: io::stdin(), stdout }));
+	}
+
+	let mut memory = match SharedMem::open(id) {
+		Ok(memory) => memory,
+		Err(e) => {
+			debug!("{} Error opening shared memory: {:?}", process::id(), e);
+			return Err(format!("Error opening shared memory: {:?}", e));
+		}
+	};
+
+	let exit = Arc::new(atomic::AtomicBool::new(false));
+	// spawn parent monitor thread
+	let watch_exit = exit.clone();
+	thread::spawn(move || {
+		let mut in_data = Vec::new();
+		// pipe terminates when parent process exits
+		io::stdin().read_to_end(&mut in_data).ok();
+		debug!("{} Parent process is dead. Exiting", process::id());
+		exit.store(true, atomic::Ordering::Relaxed);
+	});
+
+	{
+		let mut slice = memory.wlock_as_slice(0)
+			.map_err(|e| format!("Error locking shared memory: {:?}", e))?;
+		let header_buf = header_region(&mut **slice)?;
+		write_frame(header_buf, &Handshake { version: PROTOCOL_VERSION })?;
+	}
+
+	memory.set(Event::WorkerReady as usize, EventState::Signaled)
+		.map_err(|e| format!("{} Error setting shared event: {:?}", process::id(), e))?;
+
+	Ok(Box::new(ShmWorker { memory, exit: watch_exit, layout: None }))
+}
+
+/// Sent by a worker once it is ready for candidates.
+#[derive(Encode, Decode, Debug)]
+struct Handshake {
+	version: u32,
+}
+
+fn check_handshake(handshake: Handshake) -> Result<(), Error> {
+	if handshake.version != PROTOCOL_VERSION {
+		return Err(Error::Protocol(format!(
+			"Worker speaks protocol version {}, expected {}",
+			handshake.version,
+			PROTOCOL_VERSION,
+		)));
+	}
+	Ok(())
+}
+
+fn decode_payload<T: Decode>(payload: &[u8]) -> Result<T, String> {
+	T::decode(&mut &payload[..]).map_err(|e| format!("Can't decode frame: {}", e.what()))
+}
+
+/// Sizes of the regions of the shared memory following the header: the encoded executor
+/// parameters and sandbox of the pool, the code, the encoded parameters and the encoded upward
+/// messages.
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+struct MemoryLayout {
+	config_size: u64,
+	max_code_size: u64,
+	max_params_size: u64,
+	max_message_size: u64,
+}
+
+impl MemoryLayout {
+	fn new(config: &ValidationPoolConfig) -> Self {
+		MemoryLayout {
+			config_size: (&config.executor_params, &config.sandbox).encode().len() as u64,
+			max_code_size: config.max_code_size as u64,
+			max_params_size: config.max_params_size as u64,
+			max_message_size: config.max_message_size as u64,
+		}
+	}
+
+	/// Size of the shared memory, including the header. Also bounds the size of a frame sent
+	/// over the standard output of a worker.
+	fn size(&self) -> usize {
+		let regions = self.config_size
+			+ self.max_code_size
+			+ self.max_params_size
+			+ self.max_message_size;
+		HEADER_MEM + regions as usize
+	}
+}
+
+enum Event {
+	CandidateReady = 0,
+	ResultReady = 1,
+	WorkerReady = 2,
+}
+
+/// Write `value` to the start of `buf`, prefixed with its length and checksum.
+fn write_frame<T: Encode>(buf: &mut [u8], value: &T) -> Result<(), String> {
+	let payload = value.encode();
+	let available = buf.len();
+	let frame = buf.get_mut(..FRAME_PREFIX + payload.len())
+		.ok_or_else(|| format!("{} bytes don't fit in {} bytes", payload.len(), available))?;
+	frame[..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+	frame[4..FRAME_PREFIX].copy_from_slice(&blake2_256(&payload));
+	frame[FRAME_PREFIX..].copy_from_slice(&payload);
+	Ok(())
+}
+
+/// Read a value written by `write_frame` from the start of `buf`.
+fn read_frame<T: Decode>(buf: &[u8]) -> Result<T, String> {
+	let prefix = buf.get(..FRAME_PREFIX).ok_or_else(|| "Frame is truncated".to_string())?;
+	let len = frame_len(prefix);
+	let payload = buf[FRAME_PREFIX..].get(..len)
+		.ok_or_else(|| format!("Frame of {} bytes overflows its region", len))?;
+	check_payload(prefix, payload)?;
+	decode_payload(payload)
+}
+
+/// Write `value` to `stream` as a frame, prefixed with its length and checksum.
+fn write_stream_frame<T: Encode>(stream: &mut impl Write, value: &T) -> io::Result<()> {
+	let payload = value.encode();
+	stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+	stream.write_all(&blake2_256(&payload))?;
+	stream.write_all(&payload)?;
+	stream.flush()
+}
+
+/// Read the payload of a frame written by `write_stream_frame`, of at most `max_len` bytes.
+fn read_stream_frame(stream: &mut impl Read, max_len: usize) -> Result<Vec<u8>, String> {
+	let mut prefix = [0u8; FRAME_PREFIX];
+	stream.read_exact(&mut prefix).map_err(|e| format!("Can't read frame: {}", e))?;
+	let len = frame_len(&prefix);
+	if len > max_len {
+		return Err(format!("Frame of {} bytes exceeds the limit of {} bytes", len, max_len));
+	}
+
+	let mut payload = vec![0u8; len];
+	stream.read_exact(&mut payload).map_err(|e| format!("Can't read frame: {}", e))?;
+	check_payload(&prefix, &payload)?;
+	Ok(payload)
+}
+
+fn frame_len(prefix: &[u8]) -> usize {
+	let mut len = [0u8; 4];
+	len.copy_from_slice(&prefix[..4]);
+	u32::from_le_bytes(len) as usize
+}
+
+fn check_payload(prefix: &[u8], payload: &[u8]) -> Result<(), String> {
+	if blake2_256(payload)[..] != prefix[4..FRAME_PREFIX] {
+		return Err("Frame checksum mismatch".into());
+	}
+	Ok(())
+}
+
+/// The header region at the start of the shared memory `data`.
+fn header_region(data: &mut [u8]) -> Result<&mut [u8], String> {
+	data.get_mut(..HEADER_MEM).ok_or_else(|| "Shared memory is too small".to_string())
+}
+
+/// Size and checksum of the data written to the start of a region of the shared memory.
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+struct Region {
+	size: u64,
+	checksum: [u8; 32],
+}
+
+impl Region {
+	/// Write `data` to the start of `region`.
+	fn write(region: &mut [u8], data: &[u8]) -> Result<Region, String> {
+		let available = region.len();
+		region.get_mut(..data.len())
+			.ok_or_else(|| format!("{} bytes don't fit in {} bytes", data.len(), available))?
+			.copy_from_slice(data);
+		Ok(Region { size: data.len() as u64, checksum: blake2_256(data) })
+	}
+
+	/// The data this describes, at the start of `region`.
+	fn read<'a>(&self, region: &'a [u8]) -> Result<&'a [u8], String> {
+		let data = region.get(..self.size as usize).ok_or_else(|| {
+			format!("{} bytes overflow a region of {} bytes", self.size, region.len())
+		})?;
+		if blake2_256(data) != self.checksum {
+			return Err("Region checksum mismatch".into());
+		}
+		Ok(data)
+	}
+}
+
+/// The regions of the shared memory.
+struct Regions<'a> {
+	header: &'a mut [u8],
+	config: &'a mut [u8],
+	code: &'a mut [u8],
+	params: &'a mut [u8],
+	messages: &'a mut [u8],
+}
+
+impl<'a> Regions<'a> {
+	/// Split the shared memory `data` into the regions of `layout`.
+	fn split(data: &'a mut [u8], layout: &MemoryLayout) -> Result<Self, String> {
+		if data.len() < layout.size() {
+			return Err(format!(
+				"Shared memory is {} bytes, the layout needs {}",
+				data.len(),
+				layout.size(),
+			));
+		}
+
+		let (header, rest) = data.split_at_mut(HEADER_MEM);
+		let (config, rest) = rest.split_at_mut(layout.config_size as usize);
+		let (code, rest) = rest.split_at_mut(layout.max_code_size as usize);
+		let (params, rest) = rest.split_at_mut(layout.max_params_size as usize);
+		let (messages, _) = rest.split_at_mut(layout.max_message_size as usize);
+		Ok(Regions { header, config, code, params, messages })
+	}
+}
+
+/// Params header in shared memory.
+#[derive(Encode, Decode, Debug)]
+struct ValidationHeader {
+	/// The code region, or `None` if it still holds the code of the previous candidate.
+	code: Option<Region>,
+	params: Region,
+	/// The encoded executor parameters and sandbox, which don't fit in the header with long
+	/// lists of host functions.
+	config: Region,
+	layout: MemoryLayout,
+}
+
+/// Result header in shared memory.
+#[derive(Encode, Decode, Debug)]
+enum ValidationResultHeader {
+	/// The candidate was validated. The encoded `ValidationOutcome` is in the parameters region,
+	/// and the encoded upward messages in the message region.
+	Ok {
+		outcome: Region,
+		messages: Region,
+	},
+	/// Validation failed, with the fault of the error.
+	Error(ErrorKind, String),
+}
+
+impl ValidationResultHeader {
+	/// An error header, truncating `message` so the header fits in `HEADER_MEM`.
+	fn error(kind: ErrorKind, mut message: String) -> Self {
+		if message.len() > MAX_ERROR_MESSAGE {
+			let mut end = MAX_ERROR_MESSAGE;
+			while !message.is_char_boundary(end) {
+				end -= 1;
+			}
+			message.truncate(end);
+		}
+		ValidationResultHeader::Error(kind, message)
+	}
+}
+
+fn create_memory(layout: MemoryLayout) -> Result<SharedMem, Error> {
+	let mem_size = layout.size();
+	let mem_config = SharedMemConf::default()
+		.set_size(mem_size)
+		.add_lock(shared_memory::LockType::Mutex, 0, mem_size)?
+		.add_event(shared_memory::EventType::Auto)?  // Event::CandidateReady
+		.add_event(shared_memory::EventType::Auto)?  // Event::ResultReady
+		.add_event(shared_memory::EventType::Auto)?; // Event::WorkerReady
+
+	Ok(mem_config.create()?)
+}
+
+fn read_shm_handshake(memory: &mut SharedMem, start_timeout: Duration) -> Result<Handshake, Error> {
+	memory.wait(Event::WorkerReady as usize, timeout(start_timeout))?;
+	let data: &mut [u8] = &mut **memory.wlock_as_slice(0)?;
+	header_region(data)
+		.and_then(|header| read_frame(header))
+		.map_err(|e| Error::Protocol(format!("Invalid handshake: {}", e)))
+}
+
+fn timeout(duration: Duration) -> shared_memory::Timeout {
+	shared_memory::Timeout::Milli(duration.as_millis() as usize)
+}
+
+/// The host end of `Transport::SharedMemory`.
+struct ShmHost {
+	memory: SharedMem,
+	layout: MemoryLayout,
+}
+
+unsafe impl Send for ShmHost {}
+
+impl HostChannel for ShmHost {
+	fn send(&mut self, candidate: &Candidate) -> Result<(), Error> {
+		{
+			let data: &mut [u8] = &mut **self.memory.wlock_as_slice(0)?;
+			write_shm_candidate(data, self.layout, candidate).map_err(Error::Protocol)?;
+		}
+
+		self.memory.set(Event::CandidateReady as usize, EventState::Signaled)?;
+		Ok(())
+	}
+
+	fn receive(&mut self, timeout_after: Duration) -> Result<Response, Error> {
+		if let Err(e) = self.memory.wait(Event::ResultReady as usize, timeout(timeout_after)) {
+			debug!("Worker timeout: {:?}", e);
+			return Err(Error::Timeout);
+		}
+
+		let data: &mut [u8] = &mut **self.memory.wlock_as_slice(0)?;
+		read_shm_response(data, &self.layout).map_err(Error::Protocol)
+	}
+}
+
+/// Write `candidate` to the shared memory `data`.
+fn write_shm_candidate(
+	data: &mut [u8],
+	layout: MemoryLayout,
+	candidate: &Candidate,
+) -> Result<(), String> {
+	let regions = Regions::split(data, &layout)?;
+	let code = match candidate.code {
+		Some(ref code) => Some(Region::write(regions.code, code)?),
+		None => None,
+	};
+	let config = (&candidate.executor_params, &candidate.sandbox).encode();
+	let header = ValidationHeader {
+		code,
+		params: Region::write(regions.params, &candidate.params)?,
+		config: Region::write(regions.config, &config)?,
+		layout,
+	};
+	write_frame(regions.header, &header)
+}
+
+/// Read a candidate from the shared memory `data`, with the layout its response follows.
+fn read_shm_candidate(data: &mut [u8]) -> Result<(MemoryLayout, Candidate), String> {
+	let header: ValidationHeader = read_frame(header_region(data)?)?;
+	debug!("{} Candidate header: {:?}", process::id(), header);
+
+	let regions = Regions::split(data, &header.layout)?;
+	let (executor_params, sandbox) = decode_payload(header.config.read(regions.config)?)?;
+	let candidate = Candidate {
+		code: match header.code {
+			Some(code) => Some(code.read(regions.code)?.to_vec()),
+			None => None,
+		},
+		params: header.params.read(regions.params)?.to_vec(),
+		executor_params,
+		max_message_size: header.layout.max_message_size,
+		sandbox,
+	};
+	Ok((header.layout, candidate))
+}
+
+/// Read the response of a worker from the shared memory `data`.
+fn read_shm_response(data: &mut [u8], layout: &MemoryLayout) -> Result<Response, String> {
+	let regions = Regions::split(data, layout)?;
+	match read_frame(regions.header)? {
+		ValidationResultHeader::Ok { outcome, messages } => Ok(Response::Ok {
+			outcome: outcome.read(regions.params)?.to_vec(),
+			messages: messages.read(regions.messages)?.to_vec(),
+		}),
+		ValidationResultHeader::Error(kind, message) => Ok(Response::Error(kind, message)),
+	}
+}
+
+/// The worker end of `Transport::SharedMemory`.
+struct ShmWorker {
+	memory: SharedMem,
+	/// Set once the host process is gone.
+	exit: Arc<atomic::AtomicBool>,
+	/// Layout of the last candidate, which the response follows.
+	layout: Option<MemoryLayout>,
+}
+
+impl WorkerChannel for ShmWorker {
+	fn receive(&mut self) -> Result<Option<Candidate>, String> {
+		loop {
+			if self.exit.load(atomic::Ordering::Relaxed) {
+				return Ok(None);
+			}
+
+			debug!("{} Waiting for candidate", process::id());
+			match self.memory.wait(Event::CandidateReady as usize, shared_memory::Timeout::Sec(3)) {
+				Err(e) => {
+					// Timeout
+					trace!("{} Timeout waiting for candidate: {:?}", process::id(), e);
+					continue;
+				}
+				Ok(()) => break,
+			}
+		}
+
+		debug!("{} Processing candidate", process::id());
+		let mut slice = self.memory.wlock_as_slice(0)
+			.map_err(|e| format!("Error locking shared memory: {:?}", e))?;
+		let data: &mut [u8] = &mut **slice;
+		self.layout = None;
+		let (layout, candidate) = read_shm_candidate(data)?;
+		self.layout = Some(layout);
+		Ok(Some(candidate))
+	}
+
+	fn send(&mut self, response: Response) -> Result<(), String> {
+		{
+			let mut slice = self.memory.wlock_as_slice(0)
+				.map_err(|e| format!("Error locking shared memory: {:?}", e))?;
+			let data: &mut [u8] = &mut **slice;
+			let result = write_shm_response(data, self.layout, response).unwrap_or_else(|e| {
+				ValidationResultHeader::error(
+					ErrorKind::Internal,
+					format!("Can't send the result: {}", e),
+				)
+			});
+			write_frame(header_region(data)?, &result)?;
+		}
+
+		debug!("{} Signaling result", process::id());
+		self.memory.set(Event::ResultReady as usize, EventState::Signaled)
+			.map_err(|e| format!("Error setting shared event: {:?}", e))
+	}
+}
+
+/// Write the data of `response` to the shared memory `data`, returning the header describing
+/// it. The parameters were used, so their region holds the outcome, and error messages are
+/// truncated to fit in the header.
+fn write_shm_response(
+	data: &mut [u8],
+	layout: Option<MemoryLayout>,
+	response: Response,
+) -> Result<ValidationResultHeader, String> {
+	match response {
+		Response::Ok { outcome, messages } => {
+			let layout = layout.ok_or_else(|| "No candidate was received".to_string())?;
+			let regions = Regions::split(data, &layout)?;
+			Ok(ValidationResultHeader::Ok {
+				messages: Region::write(regions.messages, &messages)?,
+				outcome: Region::write(regions.params, &outcome)?,
+			})
+		},
+		Response::Error(kind, message) => Ok(ValidationResultHeader::error(kind, message)),
+	}
+}
+
+/// Read frames from `stream` on a separate thread, so they can be waited for with a timeout.
+///
+/// The thread stops after the first error, which is usually the end of the stream.
+fn read_frames(
+	mut stream: impl Read + Send + 'static,
+	max_len: usize,
+) -> mpsc::Receiver<Result<Vec<u8>, String>> {
+	let (sender, receiver) = mpsc::channel();
+	thread::spawn(move || loop {
+		let frame = read_stream_frame(&mut stream, max_len);
+		let failed = frame.is_err();
+		if sender.send(frame).is_err() || failed {
+			break;
+		}
+	});
+	receiver
+}
+
+/// The host end of `Transport::Stdio`.
+struct StdioHost {
+	stdin: process::ChildStdin,
+	frames: mpsc::Receiver<Result<Vec<u8>, String>>,
+}
+
+impl StdioHost {
+	fn receive_frame(&mut self, timeout: Duration) -> Result<Vec<u8>, Error> {
+		match self.frames.recv_timeout(timeout) {
+			Ok(frame) => frame.map_err(Error::Protocol),
+			Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::Timeout),
+			Err(mpsc::RecvTimeoutError::Disconnected) =>
+				Err(Error::Protocol("Worker closed its output".into())),
+		}
+	}
+}
+
+impl HostChannel for StdioHost {
+	fn send(&mut self, candidate: &Candidate) -> Result<(), Error> {
+		Ok(write_stream_frame(&mut self.stdin, candidate)?)
+	}
+
+	fn receive(&mut self, timeout: Duration) -> Result<Response, Error> {
+		let frame = self.receive_frame(timeout)?;
+		decode_payload(&frame).map_err(Error::Protocol)
+	}
+}
+
+/// The worker end of `Transport::Stdio`.
+struct StdioWorker {
+	stdin: io::Stdin,
+	stdout: io::Stdout,
+}
+
+impl WorkerChannel for StdioWorker {
+	fn receive(&mut self) -> Result<Option<Candidate>, String> {
+		debug!("{} Waiting for candidate", process::id());
+		// The host is trusted to bound the size of its frames.
+		let frame = match read_stream_frame(&mut self.stdin, usize::max_value()) {
+			Ok(frame) => frame,
+			Err(e) => {
+				// The host is gone, or the stream can't be followed anymore.
+				debug!("{} Exiting: {}", process::id(), e);
+				return Ok(None);
+			},
+		};
+
+		debug!("{} Processing candidate", process::id());
+		decode_payload(&frame).map(Some)
+	}
+
+	fn send(&mut self, response: Response) -> Result<(), String> {
+		debug!("{} Sending result", process::id());
+		write_stream_frame(&mut self.stdout, &response)
+			.map_err(|e| format!("Error writing the result: {}", e))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn long_lists_of_host_functions_are_sent_outside_the_header() {
+		let config = ValidationPoolConfig {
+			max_code_size: 64,
+			max_params_size: 64,
+			max_message_size: 64,
+			executor_params: ExecutorParams {
+				allowed_host_functions: Some(
+					(0..100).map(|i| format!("ext_host_function_{}", i)).collect(),
+				),
+				..Default::default()
+			},
+			sandbox: Some(SandboxConfig::default()),
+			..Default::default()
+		};
+		let layout = MemoryLayout::new(&config);
+		assert!(layout.config_size as usize > HEADER_MEM);
+
+		let candidate = Candidate {
+			code: Some(vec![1; 64]),
+			params: vec![2; 64],
+			executor_params: config.executor_params.clone(),
+			max_message_size: layout.max_message_size,
+			sandbox: config.sandbox.clone(),
+		};
+		let mut data = vec![0u8; layout.size()];
+		write_shm_candidate(&mut data, layout, &candidate).unwrap();
+
+		let (_, received) = read_shm_candidate(&mut data).unwrap();
+		assert_eq!(received.encode(), candidate.encode());
+	}
+
+	#[test]
+	fn long_errors_are_truncated_to_fit_the_header() {
+		let message = "é".repeat(HEADER_MEM);
+		let response = Response::Error(ErrorKind::InvalidCandidate, message.clone());
+		let mut data = vec![0u8; HEADER_MEM];
+		let result = write_shm_response(&mut data, None, response).unwrap();
+		write_frame(header_region(&mut data).unwrap(), &result).unwrap();
+
+		match read_frame(&data).unwrap() {
+			ValidationResultHeader::Error(kind, truncated) => {
+				assert_eq!(kind, ErrorKind::InvalidCandidate);
+				assert!(message.starts_with(&truncated));
+				assert_eq!(truncated.len(), MAX_ERROR_MESSAGE / 2 * 2);
+			},
+			header => panic!("Unexpected header: {:?}", header),
+		}
+	}
+}
@@ -16,30 +16,130 @@
 
 #![cfg(not(any(target_os = "android", target_os = "unknown")))]
 
-use std::{process, env, sync::Arc, sync::atomic, mem};
+use std::{
+	process, env, sync::{Arc, Weak}, mem, collections::VecDeque, panic, path::PathBuf, thread,
+	time::{Duration, Instant},
+};
 use codec::{Decode, Encode, EncodeAppend};
-use crate::primitives::{ValidationParams, ValidationResult, UpwardMessage};
-use super::{validate_candidate_internal, Error, Externalities};
+use futures::{channel::oneshot, future::{self, Either, Future}, FutureExt};
+use crate::primitives::{ValidationParams, UpwardMessage};
+use super::{
+	validate_candidate_internal, Error, ErrorKind, Executor, ExecutorParams, Externalities,
+	SandboxConfig, ValidationMetrics, ValidationOutcome,
+};
 use super::{MAX_CODE_MEM, MAX_RUNTIME_MEM};
-use shared_memory::{SharedMem, SharedMemConf, EventState, WriteLockable, EventWait, EventSet};
-use parking_lot::Mutex;
-use log::{debug, trace};
+use super::sandbox;
+use super::transport::{self, Candidate, HostChannel, Response, Transport, WorkerChannel};
+use parking_lot::{Condvar, Mutex};
+use sp_core::hashing::blake2_256;
+use log::debug;
 
 // Message data limit
 const MAX_MESSAGE_MEM: usize = 16 * 1024 * 1024; // 16 MiB
 
-const WORKER_ARGS_TEST: &[&'static str] = &["--nocapture", "validation_worker"];
 /// CLI Argument to start in validation worker mode.
 const WORKER_ARG: &'static str = "validation-worker";
 const WORKER_ARGS: &[&'static str] = &[WORKER_ARG];
 
-/// Execution timeout in seconds;
+/// Name of the standalone worker binary built by this crate.
+pub const WORKER_BINARY: &str = "powerplay-validation-worker";
+
+/// Execution timeout in seconds.
+///
+/// This is only a backstop for workers which hang, the execution of validation code is bounded
+/// deterministically by its fuel limit.
 #[cfg(debug_assertions)]
 pub const EXECUTION_TIMEOUT_SEC: u64 =  30;
 
 #[cfg(not(debug_assertions))]
 pub const EXECUTION_TIMEOUT_SEC: u64 =  5;
 
+/// Time a host waits before restarting a failed worker by default.
+pub const DEFAULT_RESTART_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest time a host waits before restarting a failed worker by default.
+pub const DEFAULT_MAX_RESTART_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Configuration of a `ValidationPool`.
+#[derive(Clone, Debug)]
+pub struct ValidationPoolConfig {
+	/// Number of hosts, each running a worker process.
+	pub num_hosts: usize,
+	/// Maximum number of candidates waiting for a host, beyond which validation fails with
+	/// `Error::QueueFull`.
+	pub max_queue_depth: usize,
+	/// Time a worker has to start, before it is killed.
+	pub start_timeout: Duration,
+	/// Time a worker has to validate a candidate, before it is killed.
+	pub execution_timeout: Duration,
+	/// Time a host waits before restarting a worker which crashed, timed out or broke the
+	/// protocol. Doubled after every consecutive failure, up to `max_restart_backoff`.
+	pub restart_backoff: Duration,
+	/// Longest time a host waits before restarting a failed worker.
+	pub max_restart_backoff: Duration,
+	/// Number of candidates after which a worker is replaced by a new one, if any.
+	pub max_jobs_per_worker: Option<u64>,
+	/// Resident set size in bytes beyond which a worker is replaced by a new one once it is
+	/// idle, if any. Only supported on Linux.
+	pub max_worker_rss: Option<u64>,
+	/// Maximum size of validation code, in bytes. Larger code is invalid, so every validator
+	/// must use the same limit.
+	pub max_code_size: usize,
+	/// Maximum size of the encoded validation parameters, in bytes.
+	pub max_params_size: usize,
+	/// Maximum size of the encoded upward messages of a candidate, in bytes. Candidates posting
+	/// more are invalid, so every validator must use the same limit.
+	pub max_message_size: usize,
+	/// How candidates and results are exchanged with the workers.
+	pub transport: Transport,
+	/// The executable run as worker. If `None`, this is the current executable, which must call
+	/// `run_worker` when started with `worker_args`.
+	pub worker_path: Option<PathBuf>,
+	/// Arguments starting the executable as worker. The transport appends its own, like the id
+	/// of the shared memory or `stdio`.
+	pub worker_args: Vec<String>,
+	/// Parameters of the execution of validation code by the workers.
+	pub executor_params: ExecutorParams,
+	/// The sandbox workers enter before validating their first candidate. Workers aren't
+	/// sandboxed if `None`, and fail to validate if sandboxing isn't supported.
+	pub sandbox: Option<SandboxConfig>,
+	/// Where the pool reports its metrics, if anywhere.
+	pub metrics: Option<ValidationMetrics>,
+}
+
+impl Default for ValidationPoolConfig {
+	fn default() -> Self {
+		ValidationPoolConfig {
+			num_hosts: DEFAULT_NUM_HOSTS,
+			max_queue_depth: DEFAULT_MAX_QUEUE_DEPTH,
+			start_timeout: Duration::from_secs(EXECUTION_TIMEOUT_SEC),
+			execution_timeout: Duration::from_secs(EXECUTION_TIMEOUT_SEC),
+			restart_backoff: DEFAULT_RESTART_BACKOFF,
+			max_restart_backoff: DEFAULT_MAX_RESTART_BACKOFF,
+			max_jobs_per_worker: None,
+			max_worker_rss: None,
+			max_code_size: MAX_CODE_MEM,
+			max_params_size: MAX_RUNTIME_MEM,
+			max_message_size: MAX_MESSAGE_MEM,
+			transport: Transport::default(),
+			worker_path: None,
+			worker_args: WORKER_ARGS.iter().map(|arg| arg.to_string()).collect(),
+			executor_params: ExecutorParams::default(),
+			sandbox: None,
+			metrics: None,
+		}
+	}
+}
+
+impl ValidationPoolConfig {
+	/// Run the `WORKER_BINARY` at `path` as worker, instead of the current executable.
+	pub fn with_worker_binary(mut self, path: impl Into<PathBuf>) -> Self {
+		self.worker_path = Some(path.into());
+		self.worker_args = Vec::new();
+		self
+	}
+}
+
 #[derive(Default)]
 struct WorkerExternalitiesInner {
 	up_data: Vec<u8>,
@@ -61,183 +161,671 @@ impl Externalities for WorkerExternalities {
 	}
 }
 
-enum Event {
-	CandidateReady = 0,
-	ResultReady = 1,
-	WorkerReady = 2,
-}
-
 /// A pool of hosts.
+///
+/// Every host is driven by its own thread, taking candidates from a queue shared by all hosts:
+/// the first free host validates the oldest candidate of the highest priority. The threads stop
+/// when the last clone of the pool is dropped.
 #[derive(Clone)]
 pub struct ValidationPool {
-	hosts: Arc<Vec<Mutex<ValidationHost>>>,
+	queue: Arc<JobQueue>,
+	_threads: Arc<HostThreads>,
+	states: Arc<Mutex<Vec<HostState>>>,
+	config: Arc<ValidationPoolConfig>,
+}
+
+/// Number of hosts of a pool by default.
+pub const DEFAULT_NUM_HOSTS: usize = 8;
+
+/// Maximum number of candidates waiting for a host by default.
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 256;
+
+/// Priority of a candidate in the validation queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+	/// Validated after all other candidates.
+	Low,
+	/// The priority of candidates validated with `ValidationPool::validate_candidate`.
+	Normal,
+	/// Validated before all other candidates.
+	High,
+}
+
+impl Default for Priority {
+	fn default() -> Self {
+		Priority::Normal
+	}
+}
+
+/// Validation of a candidate, run by the thread of the first free host.
+type Job = Box<dyn FnOnce(&mut ValidationHost) + Send>;
+
+#[derive(Default)]
+struct JobQueueState {
+	/// Waiting jobs, by priority.
+	jobs: [VecDeque<Job>; 3],
+	/// Set once the pool shuts down: no more jobs are accepted, and hosts stop their worker by
+	/// this deadline once no jobs are left.
+	shutdown: Option<Instant>,
+	/// Number of host threads still running.
+	running_hosts: usize,
+}
+
+struct JobQueue {
+	state: Mutex<JobQueueState>,
+	job_ready: Condvar,
+	/// Notified when the pool shuts down, waking hosts waiting to restart their worker.
+	shutdown_signal: Condvar,
+	/// Notified when a host thread stops.
+	host_stopped: Condvar,
+	max_depth: usize,
+}
+
+/// What a host waiting for a job got.
+enum Next {
+	Job(Job),
+	/// No job came before the timeout.
+	Idle,
+	/// The pool shuts down, and the worker must be stopped by the deadline.
+	Shutdown(Instant),
+}
+
+impl JobQueue {
+	fn new(max_depth: usize, num_hosts: usize) -> Self {
+		JobQueue {
+			state: Mutex::new(JobQueueState { running_hosts: num_hosts, ..Default::default() }),
+			job_ready: Condvar::new(),
+			shutdown_signal: Condvar::new(),
+			host_stopped: Condvar::new(),
+			max_depth,
+		}
+	}
+
+	/// Queue a job, unless the queue is full or the pool shuts down.
+	fn push(&self, job: Job, priority: Priority) -> Result<(), Error> {
+		let mut state = self.state.lock();
+		if state.shutdown.is_some() {
+			return Err(Error::PoolShutDown);
+		}
+		if state.jobs.iter().map(VecDeque::len).sum::<usize>() >= self.max_depth {
+			return Err(Error::QueueFull);
+		}
+
+		state.jobs[priority as usize].push_back(job);
+		self.job_ready.notify_one();
+		Ok(())
+	}
+
+	/// Queue the rest of a job a host didn't finish, before the other jobs of its priority.
+	fn requeue(&self, job: Job, priority: Priority) {
+		self.state.lock().jobs[priority as usize].push_front(job);
+		self.job_ready.notify_one();
+	}
+
+	/// Wait up to `timeout` for the next job. Queued jobs are still handed out while the pool
+	/// shuts down.
+	fn next(&self, timeout: Duration) -> Next {
+		let deadline = Instant::now() + timeout;
+		let mut state = self.state.lock();
+		loop {
+			if let Some(job) = state.jobs.iter_mut().rev().find_map(VecDeque::pop_front) {
+				return Next::Job(job);
+			}
+			if let Some(shutdown) = state.shutdown {
+				return Next::Shutdown(shutdown);
+			}
+			if self.job_ready.wait_until(&mut state, deadline).timed_out() {
+				return Next::Idle;
+			}
+		}
+	}
+
+	/// Wait for `duration`, returning early with the deadline of the shutdown if the pool shuts
+	/// down.
+	fn wait_for_shutdown(&self, duration: Duration) -> Option<Instant> {
+		let deadline = Instant::now() + duration;
+		let mut state = self.state.lock();
+		while state.shutdown.is_none() {
+			if self.shutdown_signal.wait_until(&mut state, deadline).timed_out() {
+				break;
+			}
+		}
+		state.shutdown
+	}
+
+	/// Stop accepting jobs, and tell the hosts to stop by `deadline` once the queue is empty.
+	fn shut_down(&self, deadline: Instant) {
+		let mut state = self.state.lock();
+		if state.shutdown.map_or(true, |shutdown| deadline < shutdown) {
+			state.shutdown = Some(deadline);
+		}
+		self.job_ready.notify_all();
+		self.shutdown_signal.notify_all();
+	}
+
+	/// Drop the queued jobs, failing their validation.
+	fn clear(&self) {
+		let jobs = mem::replace(&mut self.state.lock().jobs, Default::default());
+		drop(jobs);
+	}
+
+	fn host_stopped(&self) {
+		self.state.lock().running_hosts -= 1;
+		self.host_stopped.notify_all();
+	}
+}
+
+/// Stops the host threads when dropped.
+struct HostThreads(Arc<JobQueue>);
+
+impl Drop for HostThreads {
+	fn drop(&mut self) {
+		self.0.shut_down(Instant::now());
+		self.0.clear();
+	}
+}
+
+/// State of a host of a `ValidationPool`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostState {
+	/// Waiting for a candidate.
+	Idle,
+	/// Validating a candidate.
+	Busy,
+	/// Waiting to restart its worker after it failed. The host takes no candidates meanwhile.
+	Restarting,
+	/// Stopped after the pool was shut down.
+	Stopped,
+}
+
+/// Interval at which idle hosts check on their worker.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Drive the host `index` of a pool, supervising its worker: a failed worker is restarted after
+/// an exponential backoff, and workers are recycled according to the configuration.
+fn run_host(
+	index: usize,
+	queue: Arc<JobQueue>,
+	states: Arc<Mutex<Vec<HostState>>>,
+	config: Arc<ValidationPoolConfig>,
+) {
+	let set_state = |state| states.lock()[index] = state;
+	let mut host = ValidationHost::default();
+	let deadline = loop {
+		if host.failed {
+			set_state(HostState::Restarting);
+			let backoff = restart_backoff(&config, host.failures);
+			debug!("Host {} restarts its worker in {:?}", index, backoff);
+			if let Some(deadline) = queue.wait_for_shutdown(backoff) {
+				break deadline;
+			}
+			host.failed = false;
+		}
+
+		set_state(HostState::Idle);
+		let job = match queue.next(HEALTH_CHECK_INTERVAL) {
+			Next::Job(job) => job,
+			Next::Idle => {
+				host.check_worker(&config);
+				continue;
+			},
+			Next::Shutdown(deadline) => break deadline,
+		};
+
+		set_state(HostState::Busy);
+		// The host is replaced after a panic, so the pool keeps its size.
+		if panic::catch_unwind(panic::AssertUnwindSafe(|| job(&mut host))).is_err() {
+			let (failures, started_before) = (host.failures, host.started_before);
+			host = ValidationHost::default();
+			host.failures = failures;
+			host.started_before = started_before;
+			host.fail_worker();
+		}
+		host.check_worker(&config);
+	};
+
+	debug!("Host {} stops its worker", index);
+	host.stop_worker(deadline);
+	set_state(HostState::Stopped);
+	queue.host_stopped();
+}
+
+/// Time to wait before restarting a worker after `failures` consecutive failures.
+fn restart_backoff(config: &ValidationPoolConfig, failures: u32) -> Duration {
+	let factor = 1u32.checked_shl(failures.saturating_sub(1)).unwrap_or(u32::max_value());
+	config.restart_backoff.checked_mul(factor)
+		.map_or(config.max_restart_backoff, |backoff| backoff.min(config.max_restart_backoff))
+}
+
+/// CPU time used by the process `pid`, if it can be read.
+#[cfg(target_os = "linux")]
+fn cpu_time(pid: u32) -> Option<Duration> {
+	let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+	// The command name may contain spaces, the fields after it start with the state.
+	let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace().skip(11);
+	let user: u64 = fields.next()?.parse().ok()?;
+	let system: u64 = fields.next()?.parse().ok()?;
+	let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+	if ticks_per_second <= 0 {
+		return None;
+	}
+	Some(Duration::from_millis((user + system) * 1000 / ticks_per_second as u64))
 }
 
-const DEFAULT_NUM_HOSTS: usize = 8;
+#[cfg(not(target_os = "linux"))]
+fn cpu_time(_: u32) -> Option<Duration> {
+	None
+}
+
+/// Resident set size of the process `pid` in bytes, if it can be read.
+fn resident_set_size(pid: u32) -> Option<u64> {
+	let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+	let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+	let kib: u64 = line["VmRSS:".len()..].trim().trim_end_matches("kB").trim().parse().ok()?;
+	Some(kib * 1024)
+}
 
 impl ValidationPool {
 	/// Creates a validation pool with the default configuration.
 	pub fn new() -> ValidationPool {
+		ValidationPool::with_executor_params(ExecutorParams::default())
+	}
+
+	/// Creates a validation pool whose workers execute code with the given parameters.
+	pub fn with_executor_params(params: ExecutorParams) -> ValidationPool {
+		ValidationPool::with_config(ValidationPoolConfig {
+			executor_params: params,
+			..Default::default()
+		})
+	}
+
+	/// Creates a validation pool with the given configuration.
+	pub fn with_config(config: ValidationPoolConfig) -> ValidationPool {
+		let queue = Arc::new(JobQueue::new(config.max_queue_depth, config.num_hosts));
+		let states = Arc::new(Mutex::new(vec![HostState::Idle; config.num_hosts]));
+		let config = Arc::new(config);
+		for index in 0..config.num_hosts {
+			let (queue, states, config) = (queue.clone(), states.clone(), config.clone());
+			thread::Builder::new()
+				.name("validation-host".into())
+				.spawn(move || run_host(index, queue, states, config))
+				.expect("spawning a thread only fails if the OS is out of resources; qed");
+		}
+
 		ValidationPool {
-			hosts: Arc::new((0..DEFAULT_NUM_HOSTS).map(|_| Default::default()).collect()),
+			_threads: Arc::new(HostThreads(queue.clone())),
+			queue,
+			states,
+			config,
 		}
 	}
 
+	/// Shut the pool down, for all its clones: stop accepting candidates, validate the queued ones,
+	/// then let the workers exit, removing their shared memory.
+	///
+	/// Candidates which didn't get a host by `deadline` fail, and workers still running then are
+	/// killed. Validations still running are left to finish or time out. Returns whether all
+	/// hosts stopped by the deadline.
+	pub fn shutdown(&self, deadline: Instant) -> bool {
+		self.queue.shut_down(deadline);
+
+		let stopped = {
+			let mut state = self.queue.state.lock();
+			while state.running_hosts > 0 {
+				if self.queue.host_stopped.wait_until(&mut state, deadline).timed_out() {
+					break;
+				}
+			}
+			state.running_hosts == 0
+		};
+
+		self.queue.clear();
+		stopped
+	}
+
+	/// The current state of every host of the pool.
+	pub fn host_states(&self) -> Vec<HostState> {
+		self.states.lock().clone()
+	}
+
 	/// Validate a candidate under the given validation code using the next
-	/// free validation host.
+	/// free validation host, blocking until it is validated.
 	///
 	/// This will fail if the validation code is not a proper parachain validation module.
-	pub fn validate_candidate<E: Externalities>(
+	pub fn validate_candidate<E: Externalities + 'static>(
+		&self,
+		validation_code: &[u8],
+		params: ValidationParams,
+		externalities: E,
+	) -> Result<ValidationOutcome, Error> {
+		futures::executor::block_on(self.validate_candidate_async(
+			validation_code,
+			params,
+			externalities,
+			Priority::Normal,
+		))
+	}
+
+	/// Validate a candidate under the given validation code using the next free validation
+	/// host, resolving once it is validated.
+	///
+	/// Dropping the future cancels the validation if no host has started it yet. Otherwise the
+	/// host finishes it, but the upward messages are not posted to `externalities`.
+	///
+	/// Fails with `Error::QueueFull` if the maximum number of candidates are already waiting for
+	/// a host. This will fail if the validation code is not a proper parachain validation module.
+	pub fn validate_candidate_async<E: Externalities + 'static>(
 		&self,
 		validation_code: &[u8],
 		params: ValidationParams,
 		externalities: E,
-		test_mode: bool,
-	) -> Result<ValidationResult, Error> {
-		for host in self.hosts.iter() {
-			if let Some(mut host) = host.try_lock() {
-				return host.validate_candidate(validation_code, params, externalities, test_mode);
+		priority: Priority,
+	) -> impl Future<Output = Result<ValidationOutcome, Error>> {
+		let (sender, receiver) = oneshot::channel();
+		let validation_code = validation_code.to_vec();
+		let config = self.config.clone();
+		let queued = Instant::now();
+
+		let job: Job = Box::new(move |host: &mut ValidationHost| {
+			if sender.is_canceled() {
+				return;
 			}
+
+			if let Some(ref metrics) = config.metrics {
+				metrics.on_started(queued.elapsed());
+			}
+			let result = host.validate_candidate(
+				&validation_code,
+				params,
+				&config,
+				CancelableExternalities { inner: externalities, sender: &sender },
+			);
+			if let Some(ref metrics) = config.metrics {
+				metrics.on_finished(&result);
+			}
+			let _ = sender.send(result);
+		});
+
+		match self.queue.push(job, priority) {
+			Ok(()) => Either::Left(receive_result(receiver)),
+			Err(e) => Either::Right(future::ready(Err(e))),
 		}
+	}
 
-		// all workers are busy, just wait for the first one
-		self.hosts[0].lock().validate_candidate(validation_code, params, externalities, test_mode)
+	/// Validate candidates under the same validation code, blocking until all of them are
+	/// validated. The results are in the order of the candidates.
+	///
+	/// See `validate_candidates_async`.
+	pub fn validate_candidates<E: Externalities + 'static>(
+		&self,
+		validation_code: &[u8],
+		candidates: Vec<(ValidationParams, E)>,
+	) -> Vec<Result<ValidationOutcome, Error>> {
+		futures::executor::block_on(
+			self.validate_candidates_async(validation_code, candidates, Priority::Normal)
+		)
 	}
-}
 
-/// Validation worker process entry point. Runs a loop waiting for candidates to validate
-/// and sends back results via shared memory.
-pub fn run_worker(mem_id: &str) -> Result<(), String> {
-	let mut memory = match SharedMem::open(mem_id) {
-		Ok(memory) => memory,
-		Err(e) => {
-			debug!("{} Error opening shared memory: {:?}", process::id(), e);
-			return Err(format!("Error opening shared memory: {:?}", e));
+	/// Validate candidates under the same validation code, resolving once all of them are
+	/// validated. The results are in the order of the candidates.
+	///
+	/// The candidates are spread across the hosts of the pool, taking one place in the queue per
+	/// host. Every worker is sent the code once, and prepares it once.
+	pub fn validate_candidates_async<E: Externalities + 'static>(
+		&self,
+		validation_code: &[u8],
+		candidates: Vec<(ValidationParams, E)>,
+		priority: Priority,
+	) -> impl Future<Output = Vec<Result<ValidationOutcome, Error>>> {
+		let validation_code = Arc::new(validation_code.to_vec());
+		let queued = Instant::now();
+		let mut receivers = Vec::with_capacity(candidates.len());
+		let batch: VecDeque<_> = candidates.into_iter().map(|(params, externalities)| {
+			let (sender, receiver) = oneshot::channel();
+			receivers.push(receive_result(receiver));
+			(params, externalities, sender)
+		}).collect();
+		let num_jobs = std::cmp::min(batch.len(), self.config.num_hosts);
+		let batch = Arc::new(Mutex::new(batch));
+
+		for _ in 0..num_jobs {
+			let job = BatchJob {
+				batch: batch.clone(),
+				validation_code: validation_code.clone(),
+				config: self.config.clone(),
+				queued,
+				queue: Arc::downgrade(&self.queue),
+				priority,
+			};
+
+			if let Err(e) = self.queue.push(job.into_job(), priority) {
+				// The jobs already queued validate the whole batch.
+				if Arc::strong_count(&batch) == 1 {
+					for (_, _, sender) in batch.lock().drain(..) {
+						let _ = sender.send(Err(match e {
+							Error::PoolShutDown => Error::PoolShutDown,
+							_ => Error::QueueFull,
+						}));
+					}
+				}
+				break;
+			}
 		}
-	};
 
-	let worker_ext = WorkerExternalities::default();
+		future::join_all(receivers)
+	}
+}
 
-	let exit = Arc::new(atomic::AtomicBool::new(false));
-	// spawn parent monitor thread
-	let watch_exit = exit.clone();
-	std::thread::spawn(move || {
-		use std::io::Read;
-		let mut in_data = Vec::new();
-		// pipe terminates when parent process exits
-		std::io::stdin().read_to_end(&mut in_data).ok();
-		debug!("{} Parent process is dead. Exiting", process::id());
-		exit.store(true, atomic::Ordering::Relaxed);
-	});
+/// Candidates of a batch waiting for a host, with the senders of their results.
+type BatchCandidates<E> =
+	Mutex<VecDeque<(ValidationParams, E, oneshot::Sender<Result<ValidationOutcome, Error>>)>>;
 
-	memory.set(Event::WorkerReady as usize, EventState::Signaled)
-		.map_err(|e| format!("{} Error setting shared event: {:?}", process::id(), e))?;
+/// A job validating candidates of a batch until none are left.
+struct BatchJob<E> {
+	batch: Arc<BatchCandidates<E>>,
+	validation_code: Arc<Vec<u8>>,
+	config: Arc<ValidationPoolConfig>,
+	queued: Instant,
+	queue: Weak<JobQueue>,
+	priority: Priority,
+}
 
-	loop {
-		if watch_exit.load(atomic::Ordering::Relaxed) {
-			break;
-		}
+impl<E: Externalities + 'static> BatchJob<E> {
+	fn into_job(self) -> Job {
+		Box::new(move |host: &mut ValidationHost| self.run(host))
+	}
 
-		debug!("{} Waiting for candidate", process::id());
-		match memory.wait(Event::CandidateReady as usize, shared_memory::Timeout::Sec(3)) {
-			Err(e) => {
-				// Timeout
-				trace!("{} Timeout waiting for candidate: {:?}", process::id(), e);
+	fn run(self, host: &mut ValidationHost) {
+		loop {
+			let next = self.batch.lock().pop_front();
+			let (params, externalities, sender) = match next {
+				Some(candidate) => candidate,
+				None => return,
+			};
+			if sender.is_canceled() {
 				continue;
 			}
-			Ok(()) => {}
-		}
 
-		{
-			debug!("{} Processing candidate", process::id());
-			// we have candidate data
-			let mut slice = memory.wlock_as_slice(0)
-				.map_err(|e| format!("Error locking shared memory: {:?}", e))?;
-
-			let result = {
-				let data: &mut[u8] = &mut **slice;
-				let (header_buf, rest) = data.split_at_mut(1024);
-				let mut header_buf: &[u8] = header_buf;
-				let header = ValidationHeader::decode(&mut header_buf)
-					.map_err(|_| format!("Error decoding validation request."))?;
-				debug!("{} Candidate header: {:?}", process::id(), header);
-				let (code, rest) = rest.split_at_mut(MAX_CODE_MEM);
-				let (code, _) = code.split_at_mut(header.code_size as usize);
-				let (call_data, _) = rest.split_at_mut(MAX_RUNTIME_MEM);
-				let (call_data, _) = call_data.split_at_mut(header.params_size as usize);
-
-				let result = validate_candidate_internal(code, call_data, worker_ext.clone());
-				debug!("{} Candidate validated: {:?}", process::id(), result);
-
-				match result {
-					Ok(r) => {
-						let inner = worker_ext.inner.lock();
-						let up_data = &inner.up_data;
-						let up_len = up_data.len();
-
-						if up_len > MAX_MESSAGE_MEM {
-							ValidationResultHeader::Error("Message data is too large".into())
-						} else {
-							ValidationResultHeader::Ok(r)
-						}
-					},
-					Err(e) => ValidationResultHeader::Error(e.to_string()),
+			if let Some(ref metrics) = self.config.metrics {
+				metrics.on_started(self.queued.elapsed());
+			}
+			let result = host.validate_candidate(
+				&self.validation_code,
+				params,
+				&self.config,
+				CancelableExternalities { inner: externalities, sender: &sender },
+			);
+			if let Some(ref metrics) = self.config.metrics {
+				metrics.on_finished(&result);
+			}
+			let _ = sender.send(result);
+
+			// The host restarts a failed worker after a backoff, the rest of the batch waits for
+			// it or another host.
+			if host.failed {
+				if self.batch.lock().is_empty() {
+					return;
 				}
-			};
-			let mut data: &mut[u8] = &mut **slice;
-			result.encode_to(&mut data);
+				if let Some(queue) = self.queue.upgrade() {
+					let priority = self.priority;
+					queue.requeue(self.into_job(), priority);
+				}
+				return;
+			}
 		}
-		debug!("{} Signaling result", process::id());
-		memory.set(Event::ResultReady as usize, EventState::Signaled)
-			.map_err(|e| format!("Error setting shared event: {:?}", e))?;
 	}
-	Ok(())
 }
 
-/// Params header in shared memory. All offsets should be aligned to WASM page size.
-#[derive(Encode, Decode, Debug)]
-struct ValidationHeader {
-	code_size: u64,
-	params_size: u64,
+/// The result sent by a host through `receiver`.
+fn receive_result(
+	receiver: oneshot::Receiver<Result<ValidationOutcome, Error>>,
+) -> impl Future<Output = Result<ValidationOutcome, Error>> {
+	receiver.map(|result| result.unwrap_or_else(|_| {
+		Err(Error::External("Validation host stopped".into()))
+	}))
+}
+
+/// Externalities which drop the upward messages of a canceled validation.
+struct CancelableExternalities<'a, E> {
+	inner: E,
+	sender: &'a oneshot::Sender<Result<ValidationOutcome, Error>>,
+}
+
+impl<E: Externalities> Externalities for CancelableExternalities<'_, E> {
+	fn post_upward_message(&mut self, message: UpwardMessage) -> Result<(), String> {
+		if self.sender.is_canceled() {
+			return Ok(());
+		}
+
+		self.inner.post_upward_message(message)
+	}
+}
+
+/// Validation worker process entry point. Runs a loop waiting for candidates to validate
+/// and sends back results, via shared memory or, if `mem_id` is `stdio`, via the standard input
+/// and output.
+pub fn run_worker(mem_id: &str) -> Result<(), String> {
+	run_worker_with_channel(transport::open_worker(mem_id)?)
+}
+
+/// Validation worker entry point for a `Transport::Custom`, validating the candidates received
+/// from `channel` until the host is gone.
+pub fn run_worker_with_channel(mut channel: Box<dyn WorkerChannel>) -> Result<(), String> {
+	let worker_ext = WorkerExternalities::default();
+	// Kept across candidates, so the prepared code is reused while the parameters don't change.
+	let mut worker_executor: Option<Executor> = None;
+	// The code of the last candidate, which the host doesn't send again.
+	let mut worker_code: Option<Vec<u8>> = None;
+	let mut sandboxed = false;
+
+	loop {
+		let response = match channel.receive() {
+			Ok(Some(candidate)) => match prepare_sandbox(&candidate.sandbox, &mut sandboxed) {
+				Ok(()) => process_candidate(
+					candidate,
+					&mut worker_executor,
+					&mut worker_code,
+					&worker_ext,
+				),
+				Err(e) => Response::Error(
+					ErrorKind::Internal,
+					format!("Can't sandbox the worker: {}", e),
+				),
+			},
+			Ok(None) => break,
+			Err(e) => Response::Error(ErrorKind::Internal, format!("Invalid request: {}", e)),
+		};
+		channel.send(response)?;
+	}
+	Ok(())
 }
 
-#[derive(Encode, Decode, Debug)]
-pub enum ValidationResultHeader {
-	Ok(ValidationResult),
-	Error(String),
+/// Enter the sandbox before the first candidate.
+fn prepare_sandbox(config: &Option<SandboxConfig>, sandboxed: &mut bool) -> Result<(), String> {
+	match config {
+		Some(config) if !*sandboxed => {
+			debug!("{} Entering sandbox: {:?}", process::id(), config);
+			sandbox::enter(config)?;
+			*sandboxed = true;
+			Ok(())
+		},
+		_ => Ok(()),
+	}
 }
 
-unsafe impl Send for ValidationHost {}
+/// Validate `candidate`, responding with the encoded outcome and upward messages.
+fn process_candidate(
+	mut candidate: Candidate,
+	worker_executor: &mut Option<Executor>,
+	worker_code: &mut Option<Vec<u8>>,
+	worker_ext: &WorkerExternalities,
+) -> Response {
+	if let Some(code) = candidate.code.take() {
+		*worker_code = Some(code);
+	}
+	let code = match worker_code {
+		Some(ref code) => code,
+		None => return Response::Error(ErrorKind::Internal, "No validation code was sent".into()),
+	};
+	debug!(
+		"{} Candidate of {} code bytes and {} parameter bytes",
+		process::id(),
+		code.len(),
+		candidate.params.len(),
+	);
+	if worker_executor.as_ref().map_or(true, |e| *e.params() != candidate.executor_params) {
+		*worker_executor = Some(Executor::new(candidate.executor_params));
+	}
+	let executor = worker_executor.as_ref().expect("set above; qed");
+
+	let result = validate_candidate_internal(executor, code, &candidate.params, worker_ext.clone());
+	debug!("{} Candidate validated: {:?}", process::id(), result);
+
+	// Messages of this candidate, also clearing them for the next one.
+	let mut up_data = mem::replace(&mut worker_ext.inner.lock().up_data, Vec::new());
+	if up_data.is_empty() {
+		up_data = Vec::<UpwardMessage>::new().encode();
+	}
+
+	let max_message_size = candidate.max_message_size as usize;
+	let result = result.and_then(|outcome| {
+		if up_data.len() > max_message_size {
+			Err(Error::MessagesTooLarge { size: up_data.len(), max: max_message_size })
+		} else {
+			Ok(outcome)
+		}
+	});
+	match result {
+		Ok(outcome) => Response::Ok { outcome: outcome.encode(), messages: up_data },
+		Err(e) => Response::Error(e.kind(), e.to_string()),
+	}
+}
 
 #[derive(Default)]
 struct ValidationHost {
 	worker: Option<process::Child>,
-	memory: Option<SharedMem>,
+	channel: Option<Box<dyn HostChannel>>,
 	id: u32,
+	/// Number of candidates sent to the current worker.
+	jobs: u64,
+	/// Hash of the code the current worker holds, which isn't sent again.
+	code_hash: Option<[u8; 32]>,
+	/// Whether a worker was started before, which the next one replaces.
+	started_before: bool,
+	/// Number of consecutive failures of workers.
+	failures: u32,
+	/// Whether the last worker failed, and wasn't restarted yet.
+	failed: bool,
 }
 
 impl Drop for ValidationHost {
 	fn drop(&mut self) {
-		if let Some(ref mut worker) = &mut self.worker {
-			worker.kill().ok();
-		}
+		self.kill_worker();
 	}
 }
 
 impl ValidationHost {
-	fn create_memory() -> Result<SharedMem, Error> {
-		let mem_size = MAX_RUNTIME_MEM + MAX_CODE_MEM + MAX_MESSAGE_MEM + 1024;
-		let mem_config = SharedMemConf::default()
-			.set_size(mem_size)
-			.add_lock(shared_memory::LockType::Mutex, 0, mem_size)?
-			.add_event(shared_memory::EventType::Auto)?  // Event::CandidateReady
-			.add_event(shared_memory::EventType::Auto)?  // Event::ResultReady
-			.add_event(shared_memory::EventType::Auto)?; // Event::WorkerReady
-
-		Ok(mem_config.create()?)
-	}
-
-	fn start_worker(&mut self, test_mode: bool) -> Result<(), Error> {
+	fn start_worker(&mut self, config: &ValidationPoolConfig) -> Result<(), Error> {
 		if let Some(ref mut worker) = self.worker {
 			// Check if still alive
 			if let Ok(None) = worker.try_wait() {
@@ -245,26 +833,108 @@ impl ValidationHost {
 				return Ok(());
 			}
 		}
-		let memory = Self::create_memory()?;
-		let self_path = env::current_exe()?;
-		debug!("Starting worker at {:?}", self_path);
-		let mut args = if test_mode { WORKER_ARGS_TEST.to_vec() } else { WORKER_ARGS.to_vec() };
-		args.push(memory.get_os_path());
-		let worker = process::Command::new(self_path)
-			.args(args)
-			.stdin(process::Stdio::piped())
-			.spawn()?;
+		self.kill_worker();
+
+		let worker_path = match config.worker_path {
+			Some(ref path) => path.clone(),
+			None => env::current_exe()?,
+		};
+		debug!("Starting worker at {:?}", worker_path);
+		let mut command = process::Command::new(worker_path);
+		command.args(&config.worker_args);
+
+		let (worker, channel) = match transport::start_worker(&config.transport, command, config) {
+			Ok(started) => started,
+			Err(e) => {
+				self.fail_worker();
+				return Err(e);
+			},
+		};
+		match config.metrics {
+			Some(ref metrics) if self.started_before => metrics.on_worker_restart(),
+			_ => {},
+		}
+		self.started_before = true;
 		self.id = worker.id();
+		self.jobs = 0;
+		self.code_hash = None;
 		self.worker = Some(worker);
-
-		memory.wait(
-			Event::WorkerReady as usize,
-			shared_memory::Timeout::Sec(EXECUTION_TIMEOUT_SEC as usize),
-		)?;
-		self.memory = Some(memory);
+		self.channel = Some(channel);
 		Ok(())
 	}
 
+	fn kill_worker(&mut self) {
+		if let Some(mut worker) = self.worker.take() {
+			worker.kill().ok();
+			worker.wait().ok();
+		}
+		self.channel = None;
+	}
+
+	/// Let the worker exit by closing its input, killing it if it's still running at `deadline`.
+	fn stop_worker(&mut self, deadline: Instant) {
+		// The host end owns the shared memory, which is removed along with it.
+		self.channel = None;
+		let mut worker = match self.worker.take() {
+			Some(worker) => worker,
+			None => return,
+		};
+
+		drop(worker.stdin.take());
+		loop {
+			match worker.try_wait() {
+				Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+				Ok(None) => {
+					debug!("{} Killing worker", self.id);
+					worker.kill().ok();
+					worker.wait().ok();
+					break;
+				},
+				Ok(Some(_)) | Err(_) => break,
+			}
+		}
+	}
+
+	/// Kill the worker after it failed, so it is restarted after a backoff.
+	fn fail_worker(&mut self) {
+		self.kill_worker();
+		self.failures += 1;
+		self.failed = true;
+	}
+
+	/// Notice if the worker died, and recycle it if it did too many jobs, uses too much memory or
+	/// is running out of CPU time.
+	fn check_worker(&mut self, config: &ValidationPoolConfig) {
+		let worker = match self.worker {
+			Some(ref mut worker) => worker,
+			None => return,
+		};
+
+		match worker.try_wait() {
+			Ok(None) => {},
+			status => {
+				debug!("{} Worker exited: {:?}", self.id, status);
+				self.fail_worker();
+				return;
+			},
+		}
+
+		let too_many_jobs = config.max_jobs_per_worker.map_or(false, |max| self.jobs >= max);
+		let too_large = config.max_worker_rss.map_or(false, |max| {
+			resident_set_size(self.id).map_or(false, |rss| rss > max)
+		});
+		// The next candidate could use the rest of the CPU time of a sandboxed worker.
+		let out_of_time = config.sandbox.as_ref().map_or(false, |sandbox| {
+			cpu_time(self.id).map_or(false, |used| {
+				used + config.execution_timeout > sandbox.max_cpu_time
+			})
+		});
+		if too_many_jobs || too_large || out_of_time {
+			debug!("{} Recycling worker", self.id);
+			self.kill_worker();
+		}
+	}
+
 	/// Validate a candidate under the given validation code.
 	///
 	/// This will fail if the validation code is not a proper parachain validation module.
@@ -272,79 +942,172 @@ impl ValidationHost {
 		&mut self,
 		validation_code: &[u8],
 		params: ValidationParams,
+		config: &ValidationPoolConfig,
 		mut externalities: E,
-		test_mode: bool,
-	) -> Result<ValidationResult, Error> {
-		if validation_code.len() > MAX_CODE_MEM {
-			return Err(Error::CodeTooLarge(validation_code.len()));
+	) -> Result<ValidationOutcome, Error> {
+		if validation_code.len() > config.max_code_size {
+			return Err(Error::CodeTooLarge {
+				size: validation_code.len(),
+				max: config.max_code_size,
+			});
+		}
+		let encoded_params = params.encode();
+		if encoded_params.len() >= config.max_params_size {
+			return Err(Error::ParamsTooLarge {
+				size: encoded_params.len(),
+				max: config.max_params_size,
+			});
 		}
 		// First, check if need to spawn the child process
-		self.start_worker(test_mode)?;
-		let memory = self.memory.as_mut()
-			.expect("memory is always `Some` after `start_worker` completes successfully");
-		{
-			// Put data in shared mem
-			let data: &mut[u8] = &mut **memory.wlock_as_slice(0)?;
-			let (mut header_buf, rest) = data.split_at_mut(1024);
-			let (code, rest) = rest.split_at_mut(MAX_CODE_MEM);
-			let (code, _) = code.split_at_mut(validation_code.len());
-			let (call_data, _) = rest.split_at_mut(MAX_RUNTIME_MEM);
-			code[..validation_code.len()].copy_from_slice(validation_code);
-			let encoded_params = params.encode();
-			if encoded_params.len() >= MAX_RUNTIME_MEM {
-				return Err(Error::ParamsTooLarge(MAX_RUNTIME_MEM));
-			}
-			call_data[..encoded_params.len()].copy_from_slice(&encoded_params);
+		self.start_worker(config)?;
+		let channel = self.channel.as_mut()
+			.expect("channel is always `Some` after `start_worker` completes successfully");
 
-			let header = ValidationHeader {
-				code_size: validation_code.len() as u64,
-				params_size: encoded_params.len() as u64,
-			};
+		debug!("{} Sending candidate", self.id);
+		let code_hash = blake2_256(validation_code);
+		let candidate = Candidate {
+			code: match self.code_hash {
+				Some(hash) if hash == code_hash => None,
+				_ => Some(validation_code.to_vec()),
+			},
+			params: encoded_params,
+			executor_params: config.executor_params.clone(),
+			max_message_size: config.max_message_size as u64,
+			sandbox: config.sandbox.clone(),
+		};
+		debug!("{} Waiting for results", self.id);
+		self.jobs += 1;
+		let response = channel.send(&candidate)
+			.and_then(|()| channel.receive(config.execution_timeout))
+			.and_then(|response| decode_response(response).map_err(Error::Protocol));
 
-			header.encode_to(&mut header_buf);
+		if response.is_ok() {
+			self.failures = 0;
 		}
-
-		debug!("{} Signaling candidate", self.id);
-		memory.set(Event::CandidateReady as usize, EventState::Signaled)?;
-
-		debug!("{} Waiting for results", self.id);
-		match memory.wait(Event::ResultReady as usize, shared_memory::Timeout::Sec(EXECUTION_TIMEOUT_SEC as usize)) {
+		// The worker stores the code before validating the candidate, which is the only step
+		// failing with an invalid candidate. Other errors may come before, or with the worker
+		// gone.
+		self.code_hash = match response {
+			Ok(Ok(_)) | Ok(Err(Error::Worker { kind: ErrorKind::InvalidCandidate, .. })) =>
+				Some(code_hash),
+			_ => None,
+		};
+		match response {
+			Ok(Ok((outcome, upwards))) => {
+				upwards.into_iter()
+					.try_for_each(|msg| externalities.post_upward_message(msg))
+					.map_err(Error::UpwardMessageRejected)?;
+				Ok(outcome)
+			},
+			Ok(Err(e)) => {
+				debug!("{} Validation error: {}", self.id, e);
+				Err(e)
+			},
 			Err(e) => {
-				debug!("Worker timeout: {:?}", e);
-				if let Some(mut worker) = self.worker.take() {
-					worker.kill().ok();
+				debug!("{} Worker failed: {}", self.id, e);
+				match (&e, &config.metrics) {
+					(Error::Timeout, Some(metrics)) => metrics.on_worker_timeout(),
+					_ => {},
 				}
-				return Err(Error::Timeout.into());
-			}
-			Ok(()) => {}
+				// The worker hangs, or can't be trusted to follow the protocol anymore.
+				self.fail_worker();
+				Err(e)
+			},
 		}
+	}
+}
 
-		{
-			debug!("{} Reading results", self.id);
-			let data: &[u8] = &**memory.wlock_as_slice(0)?;
-			let (header_buf, rest) = data.split_at(1024);
-			let (_, rest) = rest.split_at(MAX_CODE_MEM);
-			let (_, message_data) = rest.split_at(MAX_RUNTIME_MEM);
-			let mut header_buf: &[u8] = header_buf;
-			let mut message_data: &[u8] = message_data;
-			let header = ValidationResultHeader::decode(&mut header_buf).unwrap();
-			match header {
-				ValidationResultHeader::Ok(result) => {
-					let upwards = Vec::<UpwardMessage>::decode(&mut message_data)
-						.map_err(|e|
-							Error::External(
-								format!("Could not decode upward messages: {}", e.what())
-							)
-						)?;
-					upwards.into_iter().try_for_each(|msg| externalities.post_upward_message(msg))?;
-
-					Ok(result)
-				}
-				ValidationResultHeader::Error(message) => {
-					debug!("{} Validation error: {}", self.id, message);
-					Err(Error::External(message).into())
-				}
+/// Decode the response of a worker.
+///
+/// Fails if the response doesn't follow the protocol, and returns the error of the worker if
+/// validation failed.
+fn decode_response(
+	response: Response,
+) -> Result<Result<(ValidationOutcome, Vec<UpwardMessage>), Error>, String> {
+	let (outcome, messages) = match response {
+		Response::Ok { outcome, messages } => (outcome, messages),
+		Response::Error(kind, message) => return Ok(Err(Error::Worker { kind, message })),
+	};
+
+	let outcome = ValidationOutcome::decode(&mut &outcome[..])
+		.map_err(|e| format!("Could not decode the outcome: {}", e.what()))?;
+	let upwards = Vec::<UpwardMessage>::decode(&mut &messages[..])
+		.map_err(|e| format!("Could not decode upward messages: {}", e.what()))?;
+
+	Ok(Ok((outcome, upwards)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A job recording `label` into `order` when run.
+	fn job(order: &Arc<Mutex<Vec<&'static str>>>, label: &'static str) -> Job {
+		let order = order.clone();
+		Box::new(move |_: &mut ValidationHost| order.lock().push(label))
+	}
+
+	/// Run the queued jobs, until none is left.
+	fn run_jobs(queue: &JobQueue) -> Next {
+		let mut host = ValidationHost::default();
+		loop {
+			match queue.next(Duration::from_millis(0)) {
+				Next::Job(job) => job(&mut host),
+				next => return next,
 			}
 		}
 	}
+
+	#[test]
+	fn restart_backoff_doubles_up_to_the_maximum() {
+		let config = ValidationPoolConfig {
+			restart_backoff: Duration::from_millis(100),
+			max_restart_backoff: Duration::from_millis(1000),
+			..Default::default()
+		};
+		let backoffs: Vec<_> = (1..=6).map(|failures| restart_backoff(&config, failures)).collect();
+
+		assert_eq!(backoffs, [100, 200, 400, 800, 1000, 1000].iter()
+			.map(|millis| Duration::from_millis(*millis))
+			.collect::<Vec<_>>());
+		assert_eq!(restart_backoff(&config, u32::max_value()), config.max_restart_backoff);
+	}
+
+	#[test]
+	#[cfg(target_os = "linux")]
+	fn cpu_time_is_read() {
+		let start = Instant::now();
+		while start.elapsed() < Duration::from_millis(50) {}
+		assert!(cpu_time(process::id()).unwrap() >= Duration::from_millis(10));
+	}
+
+	#[test]
+	fn jobs_are_taken_by_priority_then_in_order() {
+		let queue = JobQueue::new(8, 1);
+		let order = Arc::new(Mutex::new(Vec::new()));
+		queue.push(job(&order, "low"), Priority::Low).unwrap();
+		queue.push(job(&order, "normal 1"), Priority::Normal).unwrap();
+		queue.push(job(&order, "high"), Priority::High).unwrap();
+		queue.push(job(&order, "normal 2"), Priority::Normal).unwrap();
+
+		assert!(matches!(run_jobs(&queue), Next::Idle));
+		assert_eq!(*order.lock(), vec!["high", "normal 1", "normal 2", "low"]);
+	}
+
+	#[test]
+	fn full_queues_reject_jobs() {
+		let queue = JobQueue::new(2, 1);
+		let order = Arc::new(Mutex::new(Vec::new()));
+		queue.push(job(&order, "low"), Priority::Low).unwrap();
+		queue.push(job(&order, "normal"), Priority::Normal).unwrap();
+
+		// The limit is on all priorities together.
+		let result = queue.push(job(&order, "high"), Priority::High);
+		assert!(matches!(result, Err(Error::QueueFull)));
+
+		assert!(matches!(run_jobs(&queue), Next::Idle));
+		queue.push(job(&order, "high"), Priority::High).unwrap();
+		assert!(matches!(run_jobs(&queue), Next::Idle));
+		assert_eq!(*order.lock(), vec!["normal", "low", "high"]);
+	}
 }
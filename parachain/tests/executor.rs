@@ -0,0 +1,187 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tests of the preparation and execution of validation code.
+
+use powerplay_parachain::primitives::{
+	BlockData, HeadData, UpwardMessage, ValidationCode, ValidationParams, ValidationResult,
+};
+use powerplay_parachain::wasm_executor::{
+	validate_candidate_with_stats, validate_code, CodeError, Error, ExecutionMode, Executor,
+	ExecutorParams, Externalities, ValidationOutcome, DEFAULT_HEAP_PAGES,
+};
+
+/// Validation code acting on the first byte `n` of the block data: it grows the memory a page at
+/// a time until it fails if `n` is 0, spins forever if `n` is 255, and otherwise loops `n` times
+/// before returning empty head data.
+const CODE: &str = r#"
+	(module
+		(import "env" "memory" (memory 1))
+		(global (export "__heap_base") i32 (i32.const 1024))
+		;; An encoded `ValidationResult` with empty head data and no new code.
+		(data (i32.const 0) "\00\00")
+		(func (export "validate_block") (param $params i32) (param $len i32) (result i64)
+			(local $n i32)
+			;; The block data comes first, after its one byte length.
+			(local.set $n (i32.load8_u offset=1 (local.get $params)))
+			(if (i32.eqz (local.get $n))
+				(then (loop $grow
+					(br_if $grow (i32.ne (memory.grow (i32.const 1)) (i32.const -1))))))
+			(if (i32.eq (local.get $n) (i32.const 255))
+				(then (loop $spin (br $spin))))
+			(loop $count
+				(local.set $n (i32.sub (local.get $n) (i32.const 1)))
+				(br_if $count (local.get $n)))
+			;; Length 2 in the high half, pointer 0 in the low half.
+			(i64.const 0x200000000)))
+"#;
+
+struct Ext;
+
+impl Externalities for Ext {
+	fn post_upward_message(&mut self, _: UpwardMessage) -> Result<(), String> {
+		Ok(())
+	}
+}
+
+fn params(n: u8) -> ValidationParams {
+	ValidationParams {
+		block_data: BlockData(vec![n]),
+		parent_head: HeadData(Vec::new()),
+		max_code_size: 1024,
+		max_head_data_size: 1024,
+		relay_chain_height: 1,
+		code_upgrade_allowed: None,
+	}
+}
+
+fn validate(executor: &Executor, n: u8) -> Result<ValidationOutcome, Error> {
+	let code = wat::parse_str(CODE).unwrap();
+	validate_candidate_with_stats(&code, params(n), Ext, ExecutionMode::Local(executor))
+}
+
+#[test]
+fn fuel_used_is_deterministic() {
+	let executor = Executor::default();
+	let first = validate(&executor, 100).unwrap();
+	let second = validate(&executor, 100).unwrap();
+	let uncached = validate(&Executor::default(), 100).unwrap();
+
+	let result = ValidationResult { head_data: HeadData(Vec::new()), new_validation_code: None };
+	assert_eq!(first.result, result);
+	assert!(!first.stats.cache_hit);
+	assert!(second.stats.cache_hit);
+	assert!(first.stats.fuel_used > 0);
+	assert_eq!(first.stats.fuel_used, second.stats.fuel_used);
+	assert_eq!(first.stats.fuel_used, uncached.stats.fuel_used);
+	assert!(validate(&executor, 200).unwrap().stats.fuel_used > first.stats.fuel_used);
+}
+
+#[test]
+fn running_out_of_fuel_fails() {
+	let executor = Executor::new(ExecutorParams { fuel_limit: 1_000_000, ..Default::default() });
+	let used = validate(&executor, 100).unwrap().stats.fuel_used;
+	assert!(used < 1_000_000);
+
+	assert!(matches!(validate(&executor, 255), Err(Error::OutOfFuel)));
+
+	let executor = Executor::new(ExecutorParams { fuel_limit: used - 1, ..Default::default() });
+	assert!(matches!(validate(&executor, 100), Err(Error::OutOfFuel)));
+}
+
+#[test]
+fn exceeding_the_memory_limit_fails() {
+	// One page of memory imported by the code, and the heap pages added by the executor.
+	let initial_pages = 1 + DEFAULT_HEAP_PAGES as u32;
+	let executor = Executor::new(ExecutorParams {
+		max_memory_pages: initial_pages + 4,
+		..Default::default()
+	});
+
+	let outcome = validate(&executor, 1).unwrap();
+	assert_eq!(outcome.stats.peak_memory_pages, initial_pages);
+	assert!(matches!(validate(&executor, 0), Err(Error::MemoryLimitExceeded)));
+
+	// The heap pages alone don't fit.
+	let executor = Executor::new(ExecutorParams {
+		max_memory_pages: initial_pages - 1,
+		..Default::default()
+	});
+	assert!(matches!(validate(&executor, 1), Err(Error::MemoryLimitExceeded)));
+}
+
+#[test]
+fn grows_failing_below_the_memory_limit_return_an_error() {
+	// Code with a maximum of 2 more pages than the heap pages added by the executor, growing a
+	// page at a time until it fails, and returning the number of pages it grew.
+	let code = wat::parse_str(format!(r#"
+		(module
+			(import "env" "memory" (memory 1 {}))
+			(global (export "__heap_base") i32 (i32.const 1024))
+			;; An encoded `ValidationResult` with one byte of head data and no new code.
+			(data (i32.const 0) "\04\00\00")
+			(func (export "validate_block") (param i32 i32) (result i64)
+				(local $grown i32)
+				(loop $grow
+					(if (i32.ne (memory.grow (i32.const 1)) (i32.const -1))
+						(then
+							(local.set $grown (i32.add (local.get $grown) (i32.const 1)))
+							(br $grow))))
+				(i32.store8 offset=1 (i32.const 0) (local.get $grown))
+				(i64.const 0x300000000)))
+	"#, 1 + DEFAULT_HEAP_PAGES + 2)).unwrap();
+
+	let mode = ExecutionMode::Local(&Executor::default());
+	let outcome = validate_candidate_with_stats(&code, params(1), Ext, mode).unwrap();
+	assert_eq!(outcome.result.head_data, HeadData(vec![2]));
+	assert_eq!(outcome.stats.peak_memory_pages, 1 + DEFAULT_HEAP_PAGES as u32 + 2);
+}
+
+#[test]
+fn code_is_only_checked_at_registration() {
+	// Code using floating-point values, which `validate_code` rejects.
+	let code = wat::parse_str(r#"
+		(module
+			(import "env" "memory" (memory 1))
+			(global (export "__heap_base") i32 (i32.const 1024))
+			(data (i32.const 0) "\00\00")
+			(func (export "validate_block") (param i32 i32) (result i64)
+				(drop (f32.add (f32.const 1) (f32.const 2)))
+				(i64.const 0x200000000)))
+	"#).unwrap();
+	let error = validate_code(&ValidationCode(code.clone())).unwrap_err();
+	assert_eq!(error, CodeError::FloatingPoint);
+
+	// Code registered before the check was added keeps executing.
+	let mode = ExecutionMode::Local(&Executor::default());
+	assert!(validate_candidate_with_stats(&code, params(1), Ext, mode).is_ok());
+}
+
+#[test]
+fn code_importing_the_instrumentation_is_rejected() {
+	let code = wat::parse_str(r#"
+		(module
+			(import "env" "memory" (memory 1))
+			(import "env" "gas" (func $gas (param i32)))
+			(global (export "__heap_base") i32 (i32.const 1024))
+			(func (export "validate_block") (param i32 i32) (result i64)
+				(i64.const 0)))
+	"#).unwrap();
+
+	let mode = ExecutionMode::Local(&Executor::default());
+	let result = validate_candidate_with_stats(&code, params(1), Ext, mode);
+	assert!(matches!(result, Err(Error::WasmModule(_))));
+}
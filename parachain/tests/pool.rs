@@ -0,0 +1,535 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tests of validation pools running the worker binary.
+
+use std::{
+	collections::HashMap, process, sync::{mpsc, Arc, Mutex}, thread, time::{Duration, Instant},
+};
+use codec::{Decode, Encode};
+use powerplay_parachain::primitives::{
+	BlockData, HeadData, ParachainDispatchOrigin, UpwardMessage, ValidationParams,
+};
+use powerplay_parachain::wasm_executor::{
+	run_worker_with_channel, validate_candidate_with_stats, Candidate, Counter, CustomTransport,
+	Error, ErrorKind, ExecutionMode, Executor, Externalities, Histogram, HostChannel, HostState,
+	MetricsRegistry, Priority, Response, Transport, ValidationMetrics, ValidationOutcome,
+	ValidationPool, ValidationPoolConfig, WorkerChannel,
+};
+
+/// Validation code acting on the first byte `n` of the block data: it spins forever if `n` is
+/// 255. Otherwise it posts `n & 127` upward messages with the data `[0]`, `[1]`, ..., then traps
+/// if `n & 128` is set, or returns the head data `[n]`.
+const CODE: &str = r#"
+	(module
+		(import "env" "memory" (memory 1))
+		(import "env" "ext_parachain_post_upward_message_version_1"
+			(func $post (param i64)))
+		(global (export "__heap_base") i32 (i32.const 1024))
+		(func (export "validate_block") (param $params i32) (param $len i32) (result i64)
+			(local $n i32)
+			(local $i i32)
+			;; The block data comes first, after its one byte length.
+			(local.set $n (i32.load8_u offset=1 (local.get $params)))
+			(if (i32.eq (local.get $n) (i32.const 255))
+				(then (loop $spin (br $spin))))
+			(block $posted
+				(loop $post
+					(br_if $posted
+						(i32.ge_u (local.get $i) (i32.and (local.get $n) (i32.const 127))))
+					;; An encoded `UpwardMessage` from the `Signed` origin with the data `[i]`.
+					(i32.store8 (i32.const 0) (i32.const 0))
+					(i32.store8 (i32.const 1) (i32.const 4))
+					(i32.store8 (i32.const 2) (local.get $i))
+					(call $post (i64.const 0x300000000))
+					(local.set $i (i32.add (local.get $i) (i32.const 1)))
+					(br $post)))
+			(if (i32.ge_u (local.get $n) (i32.const 128))
+				(then unreachable))
+			;; An encoded `ValidationResult` with the head data `[n]` and no new code.
+			(i32.store8 (i32.const 16) (i32.const 4))
+			(i32.store8 (i32.const 17) (local.get $n))
+			(i32.store8 (i32.const 18) (i32.const 0))
+			;; Length 3 in the high half, pointer 16 in the low half.
+			(i64.const 0x300000010)))
+"#;
+
+/// Externalities recording the upward messages posted through them.
+#[derive(Clone, Default)]
+struct Ext(Arc<Mutex<Vec<UpwardMessage>>>);
+
+/// Externalities rejecting upward messages.
+struct Rejecting;
+
+impl Externalities for Rejecting {
+	fn post_upward_message(&mut self, _: UpwardMessage) -> Result<(), String> {
+		Err("Too many messages".into())
+	}
+}
+
+impl Ext {
+	fn messages(&self) -> Vec<Vec<u8>> {
+		self.0.lock().unwrap().iter().map(|message| message.data.clone()).collect()
+	}
+}
+
+impl Externalities for Ext {
+	fn post_upward_message(&mut self, message: UpwardMessage) -> Result<(), String> {
+		assert_eq!(message.origin, ParachainDispatchOrigin::Signed);
+		self.0.lock().unwrap().push(message);
+		Ok(())
+	}
+}
+
+/// A counter, with its counts by label values.
+struct TestCounter {
+	labels: Vec<String>,
+	counts: Mutex<HashMap<Vec<String>, u64>>,
+}
+
+impl Counter for TestCounter {
+	fn inc(&self, label_values: &[&str]) {
+		assert_eq!(label_values.len(), self.labels.len());
+		let label_values = label_values.iter().map(|value| value.to_string()).collect();
+		*self.counts.lock().unwrap().entry(label_values).or_default() += 1;
+	}
+}
+
+/// A histogram keeping the values it records.
+#[derive(Default)]
+struct TestHistogram(Mutex<Vec<f64>>);
+
+impl Histogram for TestHistogram {
+	fn observe(&self, value: f64) {
+		self.0.lock().unwrap().push(value);
+	}
+}
+
+/// A registry of the metrics of a pool, by name.
+#[derive(Default)]
+struct Registry {
+	counters: Mutex<HashMap<String, Arc<TestCounter>>>,
+	histograms: Mutex<HashMap<String, Arc<TestHistogram>>>,
+}
+
+impl MetricsRegistry for Registry {
+	fn register_counter(
+		&self,
+		name: &str,
+		_: &str,
+		labels: &[&str],
+	) -> Result<Arc<dyn Counter>, String> {
+		let counter = Arc::new(TestCounter {
+			labels: labels.iter().map(|label| label.to_string()).collect(),
+			counts: Default::default(),
+		});
+		self.counters.lock().unwrap().insert(name.into(), counter.clone());
+		Ok(counter)
+	}
+
+	fn register_histogram(
+		&self,
+		name: &str,
+		_: &str,
+		_: &[f64],
+	) -> Result<Arc<dyn Histogram>, String> {
+		let histogram = Arc::new(TestHistogram::default());
+		self.histograms.lock().unwrap().insert(name.into(), histogram.clone());
+		Ok(histogram)
+	}
+}
+
+impl Registry {
+	/// The value of the counter `name` for `label_values`.
+	fn count(&self, name: &str, label_values: &[&str]) -> u64 {
+		let counters = self.counters.lock().unwrap();
+		let label_values: Vec<_> = label_values.iter().map(|value| value.to_string()).collect();
+		let counts = counters[name].counts.lock().unwrap();
+		counts.get(&label_values).cloned().unwrap_or(0)
+	}
+
+	/// The label names of the counter `name`.
+	fn labels(&self, name: &str) -> Vec<String> {
+		self.counters.lock().unwrap()[name].labels.clone()
+	}
+
+	/// The values recorded by the histogram `name`.
+	fn values(&self, name: &str) -> Vec<f64> {
+		self.histograms.lock().unwrap()[name].0.lock().unwrap().clone()
+	}
+}
+
+fn code() -> Vec<u8> {
+	wat::parse_str(CODE).unwrap()
+}
+
+fn params(n: u8) -> ValidationParams {
+	ValidationParams {
+		block_data: BlockData(vec![n]),
+		parent_head: HeadData(Vec::new()),
+		max_code_size: 1024,
+		max_head_data_size: 1024,
+		relay_chain_height: 1,
+		code_upgrade_allowed: None,
+	}
+}
+
+/// A transport running the worker on a thread of the host, with `cat` standing in for the
+/// worker process.
+struct ThreadTransport;
+
+impl CustomTransport for ThreadTransport {
+	fn start_worker(
+		&self,
+		_: process::Command,
+		_: &ValidationPoolConfig,
+	) -> Result<(process::Child, Box<dyn HostChannel>), Error> {
+		let (candidates, worker_candidates) = mpsc::channel();
+		let (worker_responses, responses) = mpsc::channel();
+		let worker = ThreadWorker { candidates: worker_candidates, responses: worker_responses };
+		thread::spawn(move || run_worker_with_channel(Box::new(worker)));
+
+		let process = process::Command::new("cat").stdin(process::Stdio::piped()).spawn()?;
+		Ok((process, Box::new(ThreadHost { candidates, responses })))
+	}
+}
+
+struct ThreadHost {
+	candidates: mpsc::Sender<Candidate>,
+	responses: mpsc::Receiver<Response>,
+}
+
+impl HostChannel for ThreadHost {
+	fn send(&mut self, candidate: &Candidate) -> Result<(), Error> {
+		let candidate = Candidate::decode(&mut &candidate.encode()[..]).unwrap();
+		self.candidates.send(candidate).map_err(|e| Error::External(e.to_string()))
+	}
+
+	fn receive(&mut self, timeout: Duration) -> Result<Response, Error> {
+		self.responses.recv_timeout(timeout).map_err(|_| Error::Timeout)
+	}
+}
+
+struct ThreadWorker {
+	candidates: mpsc::Receiver<Candidate>,
+	responses: mpsc::Sender<Response>,
+}
+
+impl WorkerChannel for ThreadWorker {
+	fn receive(&mut self) -> Result<Option<Candidate>, String> {
+		Ok(self.candidates.recv().ok())
+	}
+
+	fn send(&mut self, response: Response) -> Result<(), String> {
+		self.responses.send(response).map_err(|e| e.to_string())
+	}
+}
+
+/// A pool of one host running the worker binary, timing out after half a second.
+fn config() -> ValidationPoolConfig {
+	ValidationPoolConfig {
+		num_hosts: 1,
+		execution_timeout: Duration::from_millis(500),
+		transport: Transport::Stdio,
+		..Default::default()
+	}
+	.with_worker_binary(env!("CARGO_BIN_EXE_powerplay-validation-worker"))
+}
+
+/// `config()` with its metrics registered in a new registry.
+fn config_with_metrics() -> (ValidationPoolConfig, Arc<Registry>) {
+	let registry = Arc::new(Registry::default());
+	let metrics = ValidationMetrics::register(&*registry).unwrap();
+	(ValidationPoolConfig { metrics: Some(metrics), ..config() }, registry)
+}
+
+/// Wait up to a second for the only host of `pool` to be in `state`.
+fn wait_for_state(pool: &ValidationPool, state: HostState) {
+	let deadline = Instant::now() + Duration::from_secs(1);
+	while pool.host_states() != vec![state] {
+		assert!(Instant::now() < deadline, "host is {:?}", pool.host_states());
+		thread::sleep(Duration::from_millis(1));
+	}
+}
+
+fn head_data(result: &Result<ValidationOutcome, Error>) -> Vec<u8> {
+	result.as_ref().unwrap().result.head_data.0.clone()
+}
+
+#[test]
+fn worker_binary_validates_candidates() {
+	let pool = ValidationPool::with_config(config());
+	let ext = Ext::default();
+
+	let result = pool.validate_candidate(&code(), params(3), ext.clone());
+	assert_eq!(head_data(&result), vec![3]);
+	assert_eq!(ext.messages(), vec![vec![0], vec![1], vec![2]]);
+}
+
+#[test]
+fn custom_transports_connect_hosts_to_their_workers() {
+	let transport = Transport::Custom(Arc::new(ThreadTransport));
+	let pool = ValidationPool::with_config(ValidationPoolConfig { transport, ..config() });
+
+	for n in 1..3 {
+		let ext = Ext::default();
+		let result = pool.validate_candidate(&code(), params(n), ext.clone());
+		assert_eq!(head_data(&result), vec![n]);
+		assert_eq!(ext.messages().len(), n as usize);
+	}
+}
+
+#[test]
+fn candidates_posting_too_large_messages_are_invalid() {
+	let config = ValidationPoolConfig { max_message_size: 8, ..config() };
+	let pool = ValidationPool::with_config(config);
+
+	let result = pool.validate_candidate(&code(), params(1), Ext::default());
+	assert_eq!(head_data(&result), vec![1]);
+
+	let error = pool.validate_candidate(&code(), params(5), Ext::default()).unwrap_err();
+	assert!(error.to_string().contains("Upward messages are 16 bytes, max allowed is 8"));
+	assert_eq!(error.kind(), ErrorKind::InvalidCandidate);
+}
+
+#[test]
+fn missing_worker_binaries_are_internal_errors() {
+	let config = config().with_worker_binary("/nonexistent/powerplay-validation-worker");
+	let pool = ValidationPool::with_config(config);
+
+	let error = pool.validate_candidate(&code(), params(1), Ext::default()).unwrap_err();
+	assert!(matches!(error, Error::Io(_)));
+	assert_eq!(error.kind(), ErrorKind::Internal);
+}
+
+#[test]
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn sandboxed_workers_validate_candidates() {
+	use powerplay_parachain::wasm_executor::SandboxConfig;
+
+	let config = ValidationPoolConfig { sandbox: Some(SandboxConfig::default()), ..config() };
+	let pool = ValidationPool::with_config(config);
+
+	for n in 0..3 {
+		let ext = Ext::default();
+		let result = pool.validate_candidate(&code(), params(n), ext.clone());
+		assert_eq!(head_data(&result), vec![n]);
+		assert_eq!(ext.messages().len(), n as usize);
+	}
+}
+
+#[test]
+fn async_validation_resolves() {
+	let pool = ValidationPool::with_config(config());
+	let ext = Ext::default();
+
+	let validation = pool.validate_candidate_async(&code(), params(2), ext.clone(), Priority::High);
+	let result = futures::executor::block_on(validation);
+	assert_eq!(head_data(&result), vec![2]);
+	assert_eq!(ext.messages(), vec![vec![0], vec![1]]);
+}
+
+#[test]
+fn dropped_validations_are_canceled() {
+	let pool = ValidationPool::with_config(config());
+	let (busy, canceled) = (Ext::default(), Ext::default());
+
+	// The only host is busy until the first candidate times out.
+	let first = pool.validate_candidate_async(&code(), params(255), busy, Priority::Normal);
+	let second = pool.validate_candidate_async(&code(), params(2), canceled.clone(), Priority::Low);
+	drop(second);
+
+	assert!(matches!(futures::executor::block_on(first), Err(Error::Timeout)));
+	let result = pool.validate_candidate(&code(), params(1), Ext::default());
+	assert_eq!(head_data(&result), vec![1]);
+	assert!(canceled.messages().is_empty());
+}
+
+#[test]
+fn local_and_remote_validation_post_the_same_messages() {
+	let pool = ValidationPool::with_config(config());
+	let executor = Executor::default();
+
+	for &n in &[0, 3, 128 | 2] {
+		let (local, remote) = (Ext::default(), Ext::default());
+		let mode = ExecutionMode::Local(&executor);
+		let local_result = validate_candidate_with_stats(&code(), params(n), local.clone(), mode);
+		let mode = ExecutionMode::Remote(&pool);
+		let remote_result = validate_candidate_with_stats(&code(), params(n), remote.clone(), mode);
+
+		assert_eq!(local_result.is_ok(), n < 128);
+		assert_eq!(remote_result.is_ok(), n < 128);
+		assert_eq!(local.messages(), remote.messages());
+		if let (Ok(local_outcome), Ok(remote_outcome)) = (local_result, remote_result) {
+			assert_eq!(local.messages().len(), n as usize);
+			assert_eq!(local_outcome.stats.upward_messages, n as u32);
+			assert_eq!(remote_outcome.stats.upward_messages, n as u32);
+		} else {
+			// The messages of an invalid candidate are dropped.
+			assert!(local.messages().is_empty());
+		}
+	}
+
+	let rejected = |mode| matches!(
+		validate_candidate_with_stats(&code(), params(1), Rejecting, mode),
+		Err(Error::UpwardMessageRejected(_))
+	);
+	assert!(rejected(ExecutionMode::Local(&executor)));
+	assert!(rejected(ExecutionMode::Remote(&pool)));
+}
+
+#[test]
+fn failed_workers_are_restarted_after_a_backoff() {
+	let (config, registry) = config_with_metrics();
+	let backoff = Duration::from_millis(500);
+	let pool = ValidationPool::with_config(ValidationPoolConfig {
+		restart_backoff: backoff,
+		..config
+	});
+
+	let result = pool.validate_candidate(&code(), params(255), Ext::default());
+	assert!(matches!(result, Err(Error::Timeout)));
+	wait_for_state(&pool, HostState::Restarting);
+
+	let failed = Instant::now();
+	let result = pool.validate_candidate(&code(), params(1), Ext::default());
+	assert_eq!(head_data(&result), vec![1]);
+	assert!(failed.elapsed() >= backoff / 2);
+	assert_eq!(registry.count("parachain_validation_worker_timeouts_total", &[]), 1);
+	assert_eq!(registry.count("parachain_validation_worker_restarts_total", &[]), 1);
+}
+
+#[test]
+fn workers_are_recycled_after_their_last_job() {
+	let (config, registry) = config_with_metrics();
+	let pool = ValidationPool::with_config(ValidationPoolConfig {
+		max_jobs_per_worker: Some(2),
+		..config
+	});
+
+	for n in 0..5 {
+		let result = pool.validate_candidate(&code(), params(n), Ext::default());
+		assert_eq!(head_data(&result), vec![n]);
+	}
+	// The first worker is started for the first candidate, and replaced after every 2.
+	assert_eq!(registry.count("parachain_validation_worker_restarts_total", &[]), 2);
+	assert_eq!(registry.count("parachain_validation_worker_timeouts_total", &[]), 0);
+}
+
+#[test]
+fn shutdown_drains_the_queue() {
+	let pool = ValidationPool::with_config(config());
+	let validations: Vec<_> = (1..4).map(|n| {
+		pool.validate_candidate_async(&code(), params(n), Ext::default(), Priority::Normal)
+	}).collect();
+
+	assert!(pool.shutdown(Instant::now() + Duration::from_secs(5)));
+	assert_eq!(pool.host_states(), vec![HostState::Stopped]);
+	for (n, validation) in (1..4).zip(validations) {
+		assert_eq!(head_data(&futures::executor::block_on(validation)), vec![n]);
+	}
+
+	let result = pool.validate_candidate(&code(), params(1), Ext::default());
+	assert!(matches!(result, Err(Error::PoolShutDown)));
+}
+
+#[test]
+fn candidates_without_a_host_by_the_deadline_fail() {
+	let pool = ValidationPool::with_config(config());
+	let validate = |n| {
+		pool.validate_candidate_async(&code(), params(n), Ext::default(), Priority::Normal)
+	};
+	let running = validate(255);
+	let queued = validate(1);
+	wait_for_state(&pool, HostState::Busy);
+
+	// The running candidate outlives the deadline, so the host can't stop by then.
+	assert!(!pool.shutdown(Instant::now() + Duration::from_millis(100)));
+	assert!(matches!(futures::executor::block_on(queued), Err(Error::External(_))));
+	assert!(matches!(futures::executor::block_on(running), Err(Error::Timeout)));
+}
+
+#[test]
+fn validations_are_counted_by_outcome() {
+	let (config, registry) = config_with_metrics();
+	let mut counters: Vec<_> = registry.counters.lock().unwrap().keys().cloned().collect();
+	counters.sort();
+	assert_eq!(counters, [
+		"parachain_validation_worker_restarts_total",
+		"parachain_validation_worker_timeouts_total",
+		"parachain_validations_failed_total",
+		"parachain_validations_started_total",
+		"parachain_validations_succeeded_total",
+	]);
+	assert_eq!(registry.labels("parachain_validations_failed_total"), ["error"]);
+	assert!(registry.labels("parachain_validations_started_total").is_empty());
+
+	let pool = ValidationPool::with_config(config);
+	for n in &[1, 2, 128, 255] {
+		let _ = pool.validate_candidate(&code(), params(*n), Ext::default());
+	}
+
+	assert_eq!(registry.count("parachain_validations_started_total", &[]), 4);
+	assert_eq!(registry.count("parachain_validations_succeeded_total", &[]), 2);
+	let failed = |error| registry.count("parachain_validations_failed_total", &[error]);
+	assert_eq!(failed("worker_invalid_candidate"), 1);
+	assert_eq!(failed("timeout"), 1);
+	assert_eq!(registry.count("parachain_validation_worker_timeouts_total", &[]), 1);
+
+	assert_eq!(registry.values("parachain_validation_queue_wait_seconds").len(), 4);
+	let execution_times = registry.values("parachain_validation_execution_seconds");
+	assert_eq!(execution_times.len(), 2);
+	assert!(execution_times.iter().all(|seconds| *seconds >= 0.0 && *seconds < 0.5));
+}
+
+#[test]
+fn batches_wait_for_failed_workers_to_restart() {
+	let backoff = Duration::from_millis(500);
+	let pool = ValidationPool::with_config(ValidationPoolConfig {
+		restart_backoff: backoff,
+		..config()
+	});
+	let candidates = [255, 1, 2].iter().map(|n| (params(*n), Ext::default())).collect();
+
+	let started = Instant::now();
+	let results = pool.validate_candidates(&code(), candidates);
+	assert!(matches!(results[0], Err(Error::Timeout)));
+	assert_eq!(head_data(&results[1]), vec![1]);
+	assert_eq!(head_data(&results[2]), vec![2]);
+	// The execution timeout, then the backoff.
+	assert!(started.elapsed() >= Duration::from_millis(500) + backoff);
+}
+
+#[test]
+fn batches_are_validated_in_order() {
+	let pool = ValidationPool::with_config(ValidationPoolConfig { num_hosts: 2, ..config() });
+	let ns = [1, 4, 128 | 2, 3, 0, 5, 128, 2];
+	let exts: Vec<_> = ns.iter().map(|_| Ext::default()).collect();
+	let candidates = ns.iter().zip(&exts).map(|(n, ext)| (params(*n), ext.clone())).collect();
+
+	let results = pool.validate_candidates(&code(), candidates);
+	assert_eq!(results.len(), ns.len());
+	for ((n, result), ext) in ns.iter().zip(&results).zip(&exts) {
+		if n & 128 == 0 {
+			// The workers keep the code after invalid candidates.
+			assert_eq!(head_data(result), vec![*n]);
+			assert_eq!(ext.messages(), (0..*n).map(|i| vec![i]).collect::<Vec<_>>());
+		} else {
+			assert!(matches!(result, Err(Error::Worker { kind: ErrorKind::InvalidCandidate, .. })));
+			assert!(ext.messages().is_empty());
+		}
+	}
+}
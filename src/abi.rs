@@ -0,0 +1,220 @@
+//! Machine-readable description of the methods a contract exposes.
+//!
+//! Metadata is generated by annotating an `impl` block with [`abi`](crate::abi::abi), and
+//! describes every method with its kind, arguments and return value. Argument and return types
+//! are described by their SCALE layout, so clients can encode calls and decode results without
+//! knowing the Rust types.
+//!
+//! The arguments of a call are the SCALE encoding of the tuple of all its arguments, except those
+//! marked as callbacks.
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+
+#[cfg(feature = "std")]
+use serde::Serialize;
+
+pub use powerplay_macros::abi;
+
+#[doc(hidden)]
+pub use alloc::vec;
+
+/// The SCALE layout of a type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize))]
+#[cfg_attr(feature = "std", serde(rename_all = "snake_case"))]
+pub enum Layout {
+	/// A type without any encoded data, like `()`.
+	Unit,
+	Bool,
+	U8,
+	U16,
+	U32,
+	U64,
+	U128,
+	I8,
+	I16,
+	I32,
+	I64,
+	I128,
+	/// A UTF-8 string, encoded like `Vec<u8>`.
+	Str,
+	/// A sequence prefixed with its compact encoded length.
+	Sequence(Box<Layout>),
+	/// A fixed-size array.
+	Array {
+		len: u32,
+		element: Box<Layout>,
+	},
+	/// A tuple, with its elements in order.
+	Tuple(Vec<Layout>),
+	/// An optional value.
+	Option(Box<Layout>),
+	/// The result of a promise, which is only known once it resolves.
+	Promise,
+}
+
+/// Types with a known SCALE layout.
+pub trait TypeLayout {
+	/// The layout of the type.
+	fn layout() -> Layout;
+}
+
+/// Whether a method changes the state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize))]
+#[cfg_attr(feature = "std", serde(rename_all = "snake_case"))]
+pub enum MethodKind {
+	/// The method only reads the state.
+	View,
+	/// The method may change the state or create promises, and must be sent in a transaction.
+	Call,
+}
+
+/// An argument of a method.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize))]
+pub struct Arg {
+	/// Name of the argument.
+	pub name: &'static str,
+	/// Layout of the argument.
+	pub layout: Layout,
+	/// Whether the argument is the result of a promise, rather than passed by the caller.
+	pub callback: bool,
+}
+
+/// A method of a contract.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize))]
+pub struct Method {
+	/// Name of the method.
+	pub name: &'static str,
+	/// Whether the method is a view or a call.
+	pub kind: MethodKind,
+	/// The arguments of the method, in order.
+	pub args: Vec<Arg>,
+	/// Layout of the returned value, if any.
+	pub returns: Option<Layout>,
+}
+
+/// Metadata of a contract.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize))]
+pub struct Abi {
+	/// Name of the contract type.
+	pub name: &'static str,
+	/// All methods callable on the contract.
+	pub methods: Vec<Method>,
+}
+
+impl Abi {
+	/// Get a method by name.
+	pub fn method(&self, name: &str) -> Option<&Method> {
+		self.methods.iter().find(|m| m.name == name)
+	}
+
+	/// The metadata as JSON.
+	#[cfg(feature = "std")]
+	pub fn to_json(&self) -> String {
+		serde_json::to_string_pretty(self).expect("metadata only contains serializable types; qed")
+	}
+}
+
+macro_rules! impl_primitive_layout {
+	( $( $ty:ty => $layout:ident ),* $(,)? ) => {
+		$(
+			impl TypeLayout for $ty {
+				fn layout() -> Layout {
+					Layout::$layout
+				}
+			}
+		)*
+	}
+}
+
+impl_primitive_layout!(
+	() => Unit,
+	bool => Bool,
+	u8 => U8,
+	u16 => U16,
+	u32 => U32,
+	u64 => U64,
+	u128 => U128,
+	i8 => I8,
+	i16 => I16,
+	i32 => I32,
+	i64 => I64,
+	i128 => I128,
+	String => Str,
+	str => Str,
+);
+
+impl<T: TypeLayout + ?Sized> TypeLayout for &T {
+	fn layout() -> Layout {
+		T::layout()
+	}
+}
+
+impl<T: TypeLayout + ?Sized> TypeLayout for Box<T> {
+	fn layout() -> Layout {
+		T::layout()
+	}
+}
+
+impl<T: TypeLayout> TypeLayout for Vec<T> {
+	fn layout() -> Layout {
+		Layout::Sequence(Box::new(T::layout()))
+	}
+}
+
+impl<T: TypeLayout> TypeLayout for [T] {
+	fn layout() -> Layout {
+		Layout::Sequence(Box::new(T::layout()))
+	}
+}
+
+impl<T: TypeLayout> TypeLayout for Option<T> {
+	fn layout() -> Layout {
+		Layout::Option(Box::new(T::layout()))
+	}
+}
+
+impl<K: TypeLayout, V: TypeLayout> TypeLayout for BTreeMap<K, V> {
+	fn layout() -> Layout {
+		Layout::Sequence(Box::new(<(K, V)>::layout()))
+	}
+}
+
+macro_rules! impl_array_layout {
+	( $( $len:expr ),* ) => {
+		$(
+			impl<T: TypeLayout> TypeLayout for [T; $len] {
+				fn layout() -> Layout {
+					Layout::Array { len: $len, element: Box::new(T::layout()) }
+				}
+			}
+		)*
+	}
+}
+
+impl_array_layout!(1, 2, 3, 4, 5, 6, 7, 8, 16, 20, 32, 64);
+
+macro_rules! impl_tuple_layout {
+	( $( ( $( $name:ident ),+ ) )* ) => {
+		$(
+			impl<$( $name: TypeLayout ),+> TypeLayout for ( $( $name, )+ ) {
+				fn layout() -> Layout {
+					Layout::Tuple(vec![$( $name::layout() ),+])
+				}
+			}
+		)*
+	}
+}
+
+impl_tuple_layout!(
+	(A)
+	(A, B)
+	(A, B, C)
+	(A, B, C, D)
+	(A, B, C, D, E)
+	(A, B, C, D, E, F)
+);
@@ -0,0 +1,154 @@
+//! Contracts: wasm code deployed to an account and executed as part of the state transition.
+//!
+//! Contract code is run in a `wasmi` interpreter, which is fully deterministic. Before execution
+//! the code is instrumented with a gas counter and a stack height limiter, so every call is
+//! bounded by the gas it was given.
+//!
+//! A contract exports its callable methods as functions without parameters or results. It talks
+//! to the outside world through the host functions in the `env` module, see [`runtime`] for the
+//! full list.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+use crate::state::{AccountId, Balance, Storage};
+
+mod prepare;
+mod runtime;
+
+pub use prepare::prepare;
+
+/// Units of gas a contract call can use.
+pub type Gas = u64;
+
+/// Maximum size of contract code, in bytes.
+pub const MAX_CODE_SIZE: usize = 512 * 1024;
+
+/// Maximum number of wasm pages a contract can use.
+pub const MAX_MEMORY_PAGES: u32 = 16;
+
+/// Maximum stack height of a contract, in values.
+pub const MAX_STACK_HEIGHT: u32 = 64 * 1024;
+
+/// Gas charged for every call into a contract.
+pub const CALL_BASE_GAS: Gas = 10_000;
+
+/// An error during contract execution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContractError {
+	/// The code is larger than `MAX_CODE_SIZE`.
+	CodeTooLarge,
+	/// The code is not valid wasm, or does not follow the contract rules.
+	InvalidCode(&'static str),
+	/// The called method is not exported by the contract.
+	MethodNotFound,
+	/// The call ran out of gas.
+	OutOfGas,
+	/// A host function was called with an out of bounds pointer.
+	MemoryAccess,
+	/// A host function was called with invalid arguments.
+	InvalidArgument,
+	/// The contract code trapped.
+	Trap,
+	/// The contract tried to change state during a read-only call.
+	ReadOnly,
+}
+
+impl fmt::Display for ContractError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ContractError::CodeTooLarge =>
+				write!(f, "Contract code is larger than {} bytes", MAX_CODE_SIZE),
+			ContractError::InvalidCode(reason) => write!(f, "Invalid contract code: {}", reason),
+			ContractError::MethodNotFound => write!(f, "Contract method not found"),
+			ContractError::OutOfGas => write!(f, "Contract ran out of gas"),
+			ContractError::MemoryAccess => write!(f, "Contract memory access out of bounds"),
+			ContractError::InvalidArgument => write!(f, "Invalid host function argument"),
+			ContractError::Trap => write!(f, "Contract trapped"),
+			ContractError::ReadOnly => write!(f, "State changes are not allowed in a read-only call"),
+		}
+	}
+}
+
+/// The context a contract method is called in.
+pub struct CallContext<'a> {
+	/// The account the contract is deployed to.
+	pub current_account: AccountId,
+	/// The account which signed the originating transaction.
+	pub signer: AccountId,
+	/// The account which caused this call, either the signer or a contract.
+	pub predecessor: AccountId,
+	/// The number of the block being built.
+	pub block_number: u64,
+	/// The balance of the current account, including `deposit`.
+	pub balance: Balance,
+	/// The balance attached to this call.
+	pub deposit: Balance,
+	/// Gas available to this call.
+	pub gas_limit: Gas,
+	/// Results of the promises this call was scheduled after. `None` for failed promises.
+	pub promise_results: &'a [Option<Vec<u8>>],
+	/// Whether the call is read-only. Writing storage or creating promises traps in read-only
+	/// calls.
+	pub read_only: bool,
+}
+
+/// An action carried out by a promise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromiseAction {
+	/// Call a method on the receiver's contract.
+	FunctionCall {
+		method: String,
+		args: Vec<u8>,
+		deposit: Balance,
+		gas: Gas,
+	},
+	/// Transfer balance to the receiver.
+	Transfer {
+		amount: Balance,
+	},
+}
+
+/// A promise created by a contract call.
+///
+/// Promises are referred to by their index in `CallOutcome::promises`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Promise {
+	/// Run `action` on `receiver` once all promises in `after` are resolved.
+	Action {
+		receiver: AccountId,
+		action: PromiseAction,
+		after: Vec<u32>,
+	},
+	/// Resolved once all the given promises are resolved.
+	Joint(Vec<u32>),
+}
+
+/// The outcome of a successful contract call.
+#[derive(Debug, Default)]
+pub struct CallOutcome {
+	/// Data the method returned.
+	pub return_data: Vec<u8>,
+	/// Promise whose result should be used as the result of this call instead of `return_data`.
+	pub returned_promise: Option<u32>,
+	/// Promises created by the call.
+	pub promises: Vec<Promise>,
+	/// Gas used by the call, including gas attached to promises.
+	pub gas_used: Gas,
+	/// Log lines emitted by the call.
+	pub logs: Vec<Vec<u8>>,
+}
+
+/// Call `method` of the contract `code`, passing `input`.
+///
+/// `storage` is the storage of the current account. On error it may be left partially
+/// modified, so callers need to revert it.
+pub fn call(
+	code: &[u8],
+	method: &str,
+	input: Vec<u8>,
+	storage: &mut Storage,
+	context: &CallContext,
+) -> Result<CallOutcome, ContractError> {
+	let module = prepare(code)?;
+	runtime::execute(&module, method, input, storage, context)
+}
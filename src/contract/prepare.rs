@@ -0,0 +1,131 @@
+//! Checking and instrumenting contract code before it is run.
+
+use parity_wasm::elements::{self, External};
+use pwasm_utils::rules;
+use super::{ContractError, MAX_CODE_SIZE, MAX_MEMORY_PAGES, MAX_STACK_HEIGHT, runtime};
+
+/// Check that `code` is a valid contract and prepare it for execution.
+///
+/// This is deterministic: a given code is either always accepted or always rejected, and is
+/// always instrumented the same way.
+pub fn prepare(code: &[u8]) -> Result<wasmi::Module, ContractError> {
+	if code.len() > MAX_CODE_SIZE {
+		return Err(ContractError::CodeTooLarge);
+	}
+
+	let module: elements::Module = parity_wasm::deserialize_buffer(code)
+		.map_err(|_| ContractError::InvalidCode("can't decode wasm"))?;
+
+	check_module(&module)?;
+
+	let gas_rules = rules::Set::default().with_forbidden_floats();
+	let module = pwasm_utils::inject_gas_counter(module, &gas_rules)
+		.map_err(|_| ContractError::InvalidCode("can't inject gas counter"))?;
+	let module = pwasm_utils::stack_height::inject_limiter(module, MAX_STACK_HEIGHT)
+		.map_err(|_| ContractError::InvalidCode("can't inject stack height limiter"))?;
+
+	wasmi::Module::from_parity_wasm_module(module)
+		.map_err(|_| ContractError::InvalidCode("module validation failed"))
+}
+
+fn check_module(module: &elements::Module) -> Result<(), ContractError> {
+	if module.start_section().is_some() {
+		return Err(ContractError::InvalidCode("start function is not allowed"));
+	}
+
+	if module.memory_section().map_or(false, |s| !s.entries().is_empty()) {
+		return Err(ContractError::InvalidCode("memory must be imported"));
+	}
+
+	let imports = module.import_section().map(|s| s.entries()).unwrap_or(&[]);
+	let mut imports_memory = false;
+	for import in imports {
+		if import.module() != "env" {
+			return Err(ContractError::InvalidCode("imports must come from `env`"));
+		}
+
+		match import.external() {
+			External::Function(_) => {
+				// `gas` is injected by us, the contract must not call it itself.
+				if import.field() == "gas" || !runtime::is_host_function(import.field()) {
+					return Err(ContractError::InvalidCode("unknown host function imported"));
+				}
+			},
+			External::Memory(memory) => {
+				let limits = memory.limits();
+				if import.field() != "memory" || limits.initial() > MAX_MEMORY_PAGES {
+					return Err(ContractError::InvalidCode("invalid memory import"));
+				}
+				if limits.maximum().map_or(false, |max| max > MAX_MEMORY_PAGES) {
+					return Err(ContractError::InvalidCode("invalid memory import"));
+				}
+				imports_memory = true;
+			},
+			_ => return Err(ContractError::InvalidCode("only functions and memory can be imported")),
+		}
+	}
+
+	if !imports_memory {
+		return Err(ContractError::InvalidCode("memory must be imported"));
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::vec;
+
+	fn prepare_wat(wat: &str) -> Result<wasmi::Module, ContractError> {
+		prepare(&wat::parse_str(wat).unwrap())
+	}
+
+	#[test]
+	fn accepts_contracts() {
+		assert!(prepare_wat(r#"
+			(module
+				(import "env" "memory" (memory 1 16))
+				(import "env" "log" (func $log (param i32 i32)))
+				(func (export "call") (call $log (i32.const 0) (i32.const 0)))
+			)
+		"#).is_ok());
+	}
+
+	#[test]
+	fn rejects_invalid_contracts() {
+		let rejected = |wat| matches!(prepare_wat(wat), Err(ContractError::InvalidCode(_)));
+
+		// No memory import.
+		assert!(rejected(r#"(module (memory 1) (func (export "call")))"#));
+		// Too much memory.
+		assert!(rejected(r#"(module (import "env" "memory" (memory 1 17)))"#));
+		// Unknown and injected host functions.
+		assert!(rejected(r#"
+			(module
+				(import "env" "memory" (memory 1))
+				(import "env" "exit" (func))
+			)
+		"#));
+		assert!(rejected(r#"
+			(module
+				(import "env" "memory" (memory 1))
+				(import "env" "gas" (func (param i32)))
+			)
+		"#));
+		// A start function.
+		assert!(rejected(r#"(module (import "env" "memory" (memory 1)) (func $f) (start $f))"#));
+		// Floats.
+		assert!(rejected(r#"
+			(module
+				(import "env" "memory" (memory 1))
+				(func (export "call") (drop (f32.add (f32.const 1) (f32.const 2))))
+			)
+		"#));
+	}
+
+	#[test]
+	fn rejects_large_code() {
+		assert_eq!(prepare(&vec![0; MAX_CODE_SIZE + 1]).err(), Some(ContractError::CodeTooLarge));
+	}
+}
@@ -0,0 +1,658 @@
+//! Host functions available to contracts.
+//!
+//! All host functions live in the `env` module. Pointers and lengths are `i32`, balances are
+//! passed as pointers to 16 little-endian bytes and account ids as pointers to 32 bytes.
+//!
+//! Variable-sized data returned to the contract (storage values, promise results) is placed in a
+//! scratch buffer, which the contract reads with `scratch_len` and `scratch_read`.
+//!
+//! | Function | Signature |
+//! |----------|-----------|
+//! | `input_len` | `() -> i32` |
+//! | `input_read` | `(ptr)` |
+//! | `return_value` | `(ptr, len)` |
+//! | `scratch_len` | `() -> i32` |
+//! | `scratch_read` | `(ptr)` |
+//! | `storage_read` | `(key_ptr, key_len) -> i32`, 1 if the key exists |
+//! | `storage_write` | `(key_ptr, key_len, value_ptr, value_len)` |
+//! | `storage_remove` | `(key_ptr, key_len)` |
+//! | `current_account_id` | `(ptr)` |
+//! | `signer_account_id` | `(ptr)` |
+//! | `predecessor_account_id` | `(ptr)` |
+//! | `block_number` | `() -> i64` |
+//! | `account_balance` | `(ptr)` |
+//! | `attached_deposit` | `(ptr)` |
+//! | `prepaid_gas` | `() -> i64` |
+//! | `used_gas` | `() -> i64` |
+//! | `log` | `(ptr, len)` |
+//! | `promise_create` | `(account_ptr, method_ptr, method_len, args_ptr, args_len, amount_ptr, gas: i64) -> i32` |
+//! | `promise_then` | `(promise, account_ptr, method_ptr, method_len, args_ptr, args_len, amount_ptr, gas: i64) -> i32` |
+//! | `promise_and` | `(promises_ptr, count) -> i32`, promises as little-endian `u32`s |
+//! | `promise_transfer` | `(account_ptr, amount_ptr) -> i32` |
+//! | `promise_return` | `(promise)` |
+//! | `promise_results_count` | `() -> i32` |
+//! | `promise_result` | `(index) -> i32`, 1 if the promise succeeded |
+
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use core::{cell::RefCell, convert::TryInto, fmt};
+use wasmi::{
+	Externals, FuncInstance, FuncRef, HostError, ImportsBuilder, MemoryDescriptor, MemoryInstance,
+	MemoryRef, ModuleImportResolver, ModuleInstance, RuntimeArgs, RuntimeValue, Signature, Trap,
+	TrapKind, ValueType, memory_units::Pages,
+};
+use crate::state::{AccountId, Balance, Storage};
+use super::{
+	CallContext, CallOutcome, ContractError, Gas, Promise, PromiseAction, CALL_BASE_GAS,
+	MAX_MEMORY_PAGES,
+};
+
+/// Gas charged for every host function call.
+const HOST_CALL_GAS: Gas = 100;
+
+/// Gas charged for every byte passed between the contract and the host.
+const BYTE_GAS: Gas = 1;
+
+#[derive(Clone, Copy)]
+enum HostFn {
+	Gas,
+	InputLen,
+	InputRead,
+	ReturnValue,
+	ScratchLen,
+	ScratchRead,
+	StorageRead,
+	StorageWrite,
+	StorageRemove,
+	CurrentAccountId,
+	SignerAccountId,
+	PredecessorAccountId,
+	BlockNumber,
+	AccountBalance,
+	AttachedDeposit,
+	PrepaidGas,
+	UsedGas,
+	Log,
+	PromiseCreate,
+	PromiseThen,
+	PromiseAnd,
+	PromiseTransfer,
+	PromiseReturn,
+	PromiseResultsCount,
+	PromiseResult,
+}
+
+use ValueType::{I32, I64};
+
+/// All host functions, with their name and signature. The index into this table is the index
+/// `wasmi` passes to `invoke_index`.
+const HOST_FUNCTIONS: &[(HostFn, &str, &[ValueType], Option<ValueType>)] = &[
+	(HostFn::Gas, "gas", &[I32], None),
+	(HostFn::InputLen, "input_len", &[], Some(I32)),
+	(HostFn::InputRead, "input_read", &[I32], None),
+	(HostFn::ReturnValue, "return_value", &[I32, I32], None),
+	(HostFn::ScratchLen, "scratch_len", &[], Some(I32)),
+	(HostFn::ScratchRead, "scratch_read", &[I32], None),
+	(HostFn::StorageRead, "storage_read", &[I32, I32], Some(I32)),
+	(HostFn::StorageWrite, "storage_write", &[I32, I32, I32, I32], None),
+	(HostFn::StorageRemove, "storage_remove", &[I32, I32], None),
+	(HostFn::CurrentAccountId, "current_account_id", &[I32], None),
+	(HostFn::SignerAccountId, "signer_account_id", &[I32], None),
+	(HostFn::PredecessorAccountId, "predecessor_account_id", &[I32], None),
+	(HostFn::BlockNumber, "block_number", &[], Some(I64)),
+	(HostFn::AccountBalance, "account_balance", &[I32], None),
+	(HostFn::AttachedDeposit, "attached_deposit", &[I32], None),
+	(HostFn::PrepaidGas, "prepaid_gas", &[], Some(I64)),
+	(HostFn::UsedGas, "used_gas", &[], Some(I64)),
+	(HostFn::Log, "log", &[I32, I32], None),
+	(HostFn::PromiseCreate, "promise_create", &[I32, I32, I32, I32, I32, I32, I64], Some(I32)),
+	(HostFn::PromiseThen, "promise_then", &[I32, I32, I32, I32, I32, I32, I32, I64], Some(I32)),
+	(HostFn::PromiseAnd, "promise_and", &[I32, I32], Some(I32)),
+	(HostFn::PromiseTransfer, "promise_transfer", &[I32, I32], Some(I32)),
+	(HostFn::PromiseReturn, "promise_return", &[I32], None),
+	(HostFn::PromiseResultsCount, "promise_results_count", &[], Some(I32)),
+	(HostFn::PromiseResult, "promise_result", &[I32], Some(I32)),
+];
+
+/// Whether `name` is a host function contracts can import.
+pub(super) fn is_host_function(name: &str) -> bool {
+	HOST_FUNCTIONS.iter().any(|(_, n, _, _)| *n == name)
+}
+
+/// A `ContractError` raised from within a host function.
+#[derive(Debug)]
+struct HostTrap(ContractError);
+
+impl fmt::Display for HostTrap {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		self.0.fmt(f)
+	}
+}
+
+impl HostError for HostTrap {}
+
+fn trap(err: ContractError) -> Trap {
+	Trap::new(TrapKind::Host(Box::new(HostTrap(err))))
+}
+
+/// Resolves the imports of a contract against `HOST_FUNCTIONS`, and creates its memory.
+struct Resolver {
+	memory: RefCell<Option<MemoryRef>>,
+}
+
+impl ModuleImportResolver for Resolver {
+	fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, wasmi::Error> {
+		let index = HOST_FUNCTIONS.iter()
+			.position(|(_, name, _, _)| *name == field_name)
+			.ok_or_else(|| wasmi::Error::Instantiation(
+				alloc::format!("Unknown host function {}", field_name)
+			))?;
+		let (_, _, params, result) = HOST_FUNCTIONS[index];
+		let expected = Signature::new(params, result);
+		if signature != &expected {
+			return Err(wasmi::Error::Instantiation(
+				alloc::format!("Invalid signature for host function {}", field_name)
+			));
+		}
+
+		Ok(FuncInstance::alloc_host(expected, index))
+	}
+
+	fn resolve_memory(
+		&self,
+		field_name: &str,
+		descriptor: &MemoryDescriptor,
+	) -> Result<MemoryRef, wasmi::Error> {
+		if field_name != "memory" {
+			return Err(wasmi::Error::Instantiation(String::from("Only `memory` can be imported")));
+		}
+
+		let maximum = descriptor.maximum().unwrap_or(MAX_MEMORY_PAGES).min(MAX_MEMORY_PAGES);
+		let memory = MemoryInstance::alloc(
+			Pages(descriptor.initial() as usize),
+			Some(Pages(maximum as usize)),
+		)?;
+		*self.memory.borrow_mut() = Some(memory.clone());
+		Ok(memory)
+	}
+}
+
+/// Run `method` of the prepared contract `module`.
+pub(super) fn execute(
+	module: &wasmi::Module,
+	method: &str,
+	input: Vec<u8>,
+	storage: &mut Storage,
+	context: &CallContext,
+) -> Result<CallOutcome, ContractError> {
+	let resolver = Resolver { memory: RefCell::new(None) };
+	let instance = ModuleInstance::new(module, &ImportsBuilder::new().with_resolver("env", &resolver))
+		.map_err(|_| ContractError::InvalidCode("instantiation failed"))?
+		.assert_no_start();
+	let memory = resolver.memory.into_inner()
+		.ok_or(ContractError::InvalidCode("memory must be imported"))?;
+
+	let func = instance.export_by_name(method)
+		.and_then(|export| export.as_func().cloned())
+		.filter(|func| {
+			let signature = func.signature();
+			signature.params().is_empty() && signature.return_type().is_none()
+		})
+		.ok_or(ContractError::MethodNotFound)?;
+
+	let mut runtime = Runtime {
+		memory,
+		context,
+		storage,
+		input,
+		scratch: Vec::new(),
+		outcome: CallOutcome::default(),
+	};
+	runtime.charge(CALL_BASE_GAS).map_err(|_| ContractError::OutOfGas)?;
+
+	match FuncInstance::invoke(&func, &[], &mut runtime) {
+		Ok(_) => Ok(runtime.outcome),
+		Err(trap) => Err(match trap.kind() {
+			TrapKind::Host(err) => err.downcast_ref::<HostTrap>()
+				.map(|err| err.0.clone())
+				.unwrap_or(ContractError::Trap),
+			_ => ContractError::Trap,
+		}),
+	}
+}
+
+/// The state of a single contract call, exposed through the host functions.
+struct Runtime<'a> {
+	memory: MemoryRef,
+	context: &'a CallContext<'a>,
+	storage: &'a mut Storage,
+	input: Vec<u8>,
+	scratch: Vec<u8>,
+	outcome: CallOutcome,
+}
+
+impl<'a> Runtime<'a> {
+	fn charge(&mut self, gas: Gas) -> Result<(), Trap> {
+		let used = self.outcome.gas_used.saturating_add(gas);
+		if used > self.context.gas_limit {
+			self.outcome.gas_used = self.context.gas_limit;
+			return Err(trap(ContractError::OutOfGas));
+		}
+
+		self.outcome.gas_used = used;
+		Ok(())
+	}
+
+	fn read(&mut self, ptr: u32, len: u32) -> Result<Vec<u8>, Trap> {
+		self.charge(Gas::from(len) * BYTE_GAS)?;
+		self.memory.get(ptr, len as usize).map_err(|_| trap(ContractError::MemoryAccess))
+	}
+
+	fn write(&mut self, ptr: u32, data: &[u8]) -> Result<(), Trap> {
+		self.charge(data.len() as Gas * BYTE_GAS)?;
+		self.memory.set(ptr, data).map_err(|_| trap(ContractError::MemoryAccess))
+	}
+
+	fn read_account_id(&mut self, ptr: u32) -> Result<AccountId, Trap> {
+		let mut id = AccountId::default();
+		id.copy_from_slice(&self.read(ptr, 32)?);
+		Ok(id)
+	}
+
+	fn read_balance(&mut self, ptr: u32) -> Result<Balance, Trap> {
+		let bytes = self.read(ptr, 16)?;
+		Ok(Balance::from_le_bytes(bytes[..].try_into().expect("read 16 bytes; qed")))
+	}
+
+	fn read_string(&mut self, ptr: u32, len: u32) -> Result<String, Trap> {
+		String::from_utf8(self.read(ptr, len)?).map_err(|_| trap(ContractError::InvalidArgument))
+	}
+
+	/// Check that `promise` refers to an existing promise.
+	fn check_promise(&self, promise: u32) -> Result<(), Trap> {
+		if (promise as usize) < self.outcome.promises.len() {
+			Ok(())
+		} else {
+			Err(trap(ContractError::InvalidArgument))
+		}
+	}
+
+	fn ensure_mutable(&self) -> Result<(), Trap> {
+		if self.context.read_only {
+			Err(trap(ContractError::ReadOnly))
+		} else {
+			Ok(())
+		}
+	}
+
+	fn push_promise(&mut self, promise: Promise) -> Result<Option<RuntimeValue>, Trap> {
+		self.outcome.promises.push(promise);
+		Ok(Some(RuntimeValue::I32(self.outcome.promises.len() as i32 - 1)))
+	}
+
+	/// Read the arguments shared by `promise_create` and `promise_then`, starting at `first`,
+	/// and turn them into a promise which runs after `after`.
+	fn function_call_promise(
+		&mut self,
+		args: &RuntimeArgs,
+		first: usize,
+		after: Vec<u32>,
+	) -> Result<Promise, Trap> {
+		let receiver = self.read_account_id(args.nth_checked(first)?)?;
+		let method = self.read_string(args.nth_checked(first + 1)?, args.nth_checked(first + 2)?)?;
+		let call_args = self.read(args.nth_checked(first + 3)?, args.nth_checked(first + 4)?)?;
+		let deposit = self.read_balance(args.nth_checked(first + 5)?)?;
+		let gas = args.nth_checked::<u64>(first + 6)?;
+
+		// Gas attached to a promise is paid for by this call.
+		self.charge(gas)?;
+
+		Ok(Promise::Action {
+			receiver,
+			action: PromiseAction::FunctionCall { method, args: call_args, deposit, gas },
+			after,
+		})
+	}
+}
+
+impl<'a> Externals for Runtime<'a> {
+	fn invoke_index(
+		&mut self,
+		index: usize,
+		args: RuntimeArgs,
+	) -> Result<Option<RuntimeValue>, Trap> {
+		let host_fn = HOST_FUNCTIONS.get(index)
+			.map(|(host_fn, _, _, _)| *host_fn)
+			.ok_or_else(|| Trap::new(TrapKind::UnexpectedSignature))?;
+
+		if let HostFn::Gas = host_fn {
+			let amount: u32 = args.nth_checked(0)?;
+			self.charge(Gas::from(amount))?;
+			return Ok(None);
+		}
+
+		self.charge(HOST_CALL_GAS)?;
+		match host_fn {
+			HostFn::StorageWrite | HostFn::StorageRemove | HostFn::PromiseCreate | HostFn::PromiseThen
+				| HostFn::PromiseAnd | HostFn::PromiseTransfer | HostFn::PromiseReturn =>
+				self.ensure_mutable()?,
+			_ => {},
+		}
+
+		match host_fn {
+			HostFn::Gas => unreachable!("handled above; qed"),
+			HostFn::InputLen => Ok(Some(RuntimeValue::I32(self.input.len() as i32))),
+			HostFn::InputRead => {
+				let input = core::mem::replace(&mut self.input, Vec::new());
+				let res = self.write(args.nth_checked(0)?, &input);
+				self.input = input;
+				res.map(|_| None)
+			},
+			HostFn::ReturnValue => {
+				self.outcome.return_data = self.read(args.nth_checked(0)?, args.nth_checked(1)?)?;
+				self.outcome.returned_promise = None;
+				Ok(None)
+			},
+			HostFn::ScratchLen => Ok(Some(RuntimeValue::I32(self.scratch.len() as i32))),
+			HostFn::ScratchRead => {
+				let scratch = core::mem::replace(&mut self.scratch, Vec::new());
+				let res = self.write(args.nth_checked(0)?, &scratch);
+				self.scratch = scratch;
+				res.map(|_| None)
+			},
+			HostFn::StorageRead => {
+				let key = self.read(args.nth_checked(0)?, args.nth_checked(1)?)?;
+				let value = self.storage.get(&key).cloned();
+				let found = value.is_some();
+				self.scratch = value.unwrap_or_default();
+				self.charge(self.scratch.len() as Gas * BYTE_GAS)?;
+				Ok(Some(RuntimeValue::I32(found as i32)))
+			},
+			HostFn::StorageWrite => {
+				let key = self.read(args.nth_checked(0)?, args.nth_checked(1)?)?;
+				let value = self.read(args.nth_checked(2)?, args.nth_checked(3)?)?;
+				self.storage.insert(key, value);
+				Ok(None)
+			},
+			HostFn::StorageRemove => {
+				let key = self.read(args.nth_checked(0)?, args.nth_checked(1)?)?;
+				self.storage.remove(&key);
+				Ok(None)
+			},
+			HostFn::CurrentAccountId => {
+				let id = self.context.current_account;
+				self.write(args.nth_checked(0)?, &id).map(|_| None)
+			},
+			HostFn::SignerAccountId => {
+				let id = self.context.signer;
+				self.write(args.nth_checked(0)?, &id).map(|_| None)
+			},
+			HostFn::PredecessorAccountId => {
+				let id = self.context.predecessor;
+				self.write(args.nth_checked(0)?, &id).map(|_| None)
+			},
+			HostFn::BlockNumber => Ok(Some(RuntimeValue::I64(self.context.block_number as i64))),
+			HostFn::AccountBalance => {
+				let balance = self.context.balance.to_le_bytes();
+				self.write(args.nth_checked(0)?, &balance).map(|_| None)
+			},
+			HostFn::AttachedDeposit => {
+				let deposit = self.context.deposit.to_le_bytes();
+				self.write(args.nth_checked(0)?, &deposit).map(|_| None)
+			},
+			HostFn::PrepaidGas => Ok(Some(RuntimeValue::I64(self.context.gas_limit as i64))),
+			HostFn::UsedGas => Ok(Some(RuntimeValue::I64(self.outcome.gas_used as i64))),
+			HostFn::Log => {
+				let line = self.read(args.nth_checked(0)?, args.nth_checked(1)?)?;
+				self.outcome.logs.push(line);
+				Ok(None)
+			},
+			HostFn::PromiseCreate => {
+				let promise = self.function_call_promise(&args, 0, Vec::new())?;
+				self.push_promise(promise)
+			},
+			HostFn::PromiseThen => {
+				let after: u32 = args.nth_checked(0)?;
+				self.check_promise(after)?;
+				let promise = self.function_call_promise(&args, 1, vec![after])?;
+				self.push_promise(promise)
+			},
+			HostFn::PromiseAnd => {
+				let count: u32 = args.nth_checked(1)?;
+				let bytes = self.read(args.nth_checked(0)?, count.saturating_mul(4))?;
+				let promises = bytes.chunks(4)
+					.map(|chunk| u32::from_le_bytes(chunk.try_into().expect("chunks of 4 bytes; qed")))
+					.collect::<Vec<_>>();
+				for promise in &promises {
+					self.check_promise(*promise)?;
+				}
+				self.push_promise(Promise::Joint(promises))
+			},
+			HostFn::PromiseTransfer => {
+				let receiver = self.read_account_id(args.nth_checked(0)?)?;
+				let amount = self.read_balance(args.nth_checked(1)?)?;
+				self.push_promise(Promise::Action {
+					receiver,
+					action: PromiseAction::Transfer { amount },
+					after: Vec::new(),
+				})
+			},
+			HostFn::PromiseReturn => {
+				let promise: u32 = args.nth_checked(0)?;
+				self.check_promise(promise)?;
+				// A joint promise has no single result to return.
+				if let Promise::Joint(_) = self.outcome.promises[promise as usize] {
+					return Err(trap(ContractError::InvalidArgument));
+				}
+				self.outcome.returned_promise = Some(promise);
+				Ok(None)
+			},
+			HostFn::PromiseResultsCount =>
+				Ok(Some(RuntimeValue::I32(self.context.promise_results.len() as i32))),
+			HostFn::PromiseResult => {
+				let index: u32 = args.nth_checked(0)?;
+				let result = self.context.promise_results.get(index as usize)
+					.ok_or_else(|| trap(ContractError::InvalidArgument))?;
+				let succeeded = result.is_some();
+				self.scratch = result.clone().unwrap_or_default();
+				self.charge(self.scratch.len() as Gas * BYTE_GAS)?;
+				Ok(Some(RuntimeValue::I32(succeeded as i32)))
+			},
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::contract::call;
+
+	const CONTRACT: &str = r#"
+		(module
+			(import "env" "memory" (memory 1 1))
+			(import "env" "input_len" (func $input_len (result i32)))
+			(import "env" "input_read" (func $input_read (param i32)))
+			(import "env" "return_value" (func $return_value (param i32 i32)))
+			(import "env" "scratch_len" (func $scratch_len (result i32)))
+			(import "env" "scratch_read" (func $scratch_read (param i32)))
+			(import "env" "storage_read" (func $storage_read (param i32 i32) (result i32)))
+			(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+			(import "env" "storage_remove" (func $storage_remove (param i32 i32)))
+			(import "env" "predecessor_account_id" (func $predecessor_account_id (param i32)))
+			(import "env" "block_number" (func $block_number (result i64)))
+			(import "env" "attached_deposit" (func $attached_deposit (param i32)))
+			(import "env" "log" (func $log (param i32 i32)))
+			(import "env" "promise_create"
+				(func $promise_create (param i32 i32 i32 i32 i32 i32 i64) (result i32)))
+			(import "env" "promise_then"
+				(func $promise_then (param i32 i32 i32 i32 i32 i32 i32 i64) (result i32)))
+			(import "env" "promise_and" (func $promise_and (param i32 i32) (result i32)))
+			(import "env" "promise_transfer" (func $promise_transfer (param i32 i32) (result i32)))
+			(import "env" "promise_return" (func $promise_return (param i32)))
+
+			;; A storage key, a method name and the promise indices 0 and 1.
+			(data (i32.const 0) "key")
+			(data (i32.const 8) "answer")
+			(data (i32.const 16) "\00\00\00\00\01\00\00\00")
+
+			;; Store the input under "key", returning the previous value.
+			(func (export "swap")
+				(drop (call $storage_read (i32.const 0) (i32.const 3)))
+				(call $scratch_read (i32.const 1024))
+				(call $input_read (i32.const 512))
+				(call $storage_write (i32.const 0) (i32.const 3) (i32.const 512) (call $input_len))
+				(call $return_value (i32.const 1024) (call $scratch_len)))
+
+			(func (export "remove")
+				(call $storage_remove (i32.const 0) (i32.const 3)))
+
+			;; Return the predecessor, the block number and the attached deposit.
+			(func (export "context")
+				(call $predecessor_account_id (i32.const 64))
+				(i64.store (i32.const 96) (call $block_number))
+				(call $attached_deposit (i32.const 104))
+				(call $return_value (i32.const 64) (i32.const 56)))
+
+			(func (export "log")
+				(call $log (i32.const 0) (i32.const 3)))
+
+			;; Transfer the deposit back and call "answer" on the predecessor, then call "answer"
+			;; again with the results of both, and return the result of that.
+			(func (export "promises")
+				(call $predecessor_account_id (i32.const 64))
+				(call $attached_deposit (i32.const 104))
+				(drop (call $promise_transfer (i32.const 64) (i32.const 104)))
+				(drop (call $promise_create
+					(i32.const 64) (i32.const 8) (i32.const 6) (i32.const 0) (i32.const 3)
+					(i32.const 104) (i64.const 1000)))
+				(drop (call $promise_and (i32.const 16) (i32.const 2)))
+				(call $promise_return (call $promise_then
+					(i32.const 2) (i32.const 64) (i32.const 8) (i32.const 6) (i32.const 0)
+					(i32.const 0) (i32.const 104) (i64.const 1000))))
+
+			;; Return a joint promise, which has no single result.
+			(func (export "return_joint")
+				(drop (call $promise_transfer (i32.const 64) (i32.const 104)))
+				(drop (call $promise_transfer (i32.const 64) (i32.const 104)))
+				(call $promise_return (call $promise_and (i32.const 16) (i32.const 2))))
+
+			(func (export "spin")
+				(loop $spin (br $spin)))
+
+			(func (export "out_of_bounds")
+				(call $return_value (i32.const 65535) (i32.const 2)))
+		)
+	"#;
+
+	fn context(read_only: bool) -> CallContext<'static> {
+		CallContext {
+			current_account: [1; 32],
+			signer: [2; 32],
+			predecessor: [3; 32],
+			block_number: 7,
+			balance: 100,
+			deposit: 5,
+			gas_limit: 1_000_000,
+			promise_results: &[],
+			read_only,
+		}
+	}
+
+	fn run(
+		method: &str,
+		input: &[u8],
+		storage: &mut Storage,
+		read_only: bool,
+	) -> Result<CallOutcome, ContractError> {
+		let code = wat::parse_str(CONTRACT).unwrap();
+		call(&code, method, input.to_vec(), storage, &context(read_only))
+	}
+
+	#[test]
+	fn storage_functions_work() {
+		let mut storage = Storage::new();
+		let outcome = run("swap", b"a", &mut storage, false).unwrap();
+		assert!(outcome.return_data.is_empty());
+		assert_eq!(storage.get(&b"key"[..]), Some(&b"a".to_vec()));
+
+		let outcome = run("swap", b"bc", &mut storage, false).unwrap();
+		assert_eq!(outcome.return_data, b"a");
+		assert_eq!(storage.get(&b"key"[..]), Some(&b"bc".to_vec()));
+
+		run("remove", b"", &mut storage, false).unwrap();
+		assert!(storage.is_empty());
+	}
+
+	#[test]
+	fn context_functions_work() {
+		let outcome = run("context", b"", &mut Storage::new(), false).unwrap();
+		let mut expected = vec![3; 32];
+		expected.extend_from_slice(&7u64.to_le_bytes());
+		expected.extend_from_slice(&5u128.to_le_bytes());
+		assert_eq!(outcome.return_data, expected);
+
+		let outcome = run("log", b"", &mut Storage::new(), false).unwrap();
+		assert_eq!(outcome.logs, vec![b"key".to_vec()]);
+	}
+
+	#[test]
+	fn promises_are_recorded_in_order() {
+		let outcome = run("promises", b"", &mut Storage::new(), false).unwrap();
+		let answer = |args: &[u8], after| Promise::Action {
+			receiver: [3; 32],
+			action: PromiseAction::FunctionCall {
+				method: "answer".into(),
+				args: args.to_vec(),
+				deposit: 5,
+				gas: 1000,
+			},
+			after,
+		};
+		assert_eq!(outcome.promises, vec![
+			Promise::Action {
+				receiver: [3; 32],
+				action: PromiseAction::Transfer { amount: 5 },
+				after: Vec::new(),
+			},
+			answer(b"key", Vec::new()),
+			Promise::Joint(vec![0, 1]),
+			answer(b"", vec![2]),
+		]);
+		assert_eq!(outcome.returned_promise, Some(3));
+		// The gas attached to the promises is paid for by the call.
+		assert!(outcome.gas_used > CALL_BASE_GAS + 2000);
+	}
+
+	#[test]
+	fn joint_promises_cant_be_returned() {
+		assert_eq!(
+			run("return_joint", b"", &mut Storage::new(), false).unwrap_err(),
+			ContractError::InvalidArgument,
+		);
+	}
+
+	#[test]
+	fn read_only_calls_cant_change_state() {
+		let mut storage = Storage::new();
+		assert_eq!(run("swap", b"a", &mut storage, true).unwrap_err(), ContractError::ReadOnly);
+		assert!(storage.is_empty());
+		assert_eq!(run("promises", b"", &mut storage, true).unwrap_err(), ContractError::ReadOnly);
+		assert!(run("context", b"", &mut storage, true).is_ok());
+	}
+
+	#[test]
+	fn calls_are_bounded() {
+		assert_eq!(
+			run("spin", b"", &mut Storage::new(), false).unwrap_err(),
+			ContractError::OutOfGas,
+		);
+		assert_eq!(
+			run("out_of_bounds", b"", &mut Storage::new(), false).unwrap_err(),
+			ContractError::MemoryAccess,
+		);
+		assert_eq!(
+			run("missing", b"", &mut Storage::new(), false).unwrap_err(),
+			ContractError::MethodNotFound,
+		);
+	}
+}
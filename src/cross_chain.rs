@@ -0,0 +1,329 @@
+//! Cross-chain methods of the parachain, callable like the methods of a contract.
+//!
+//! A method runs in an [`Env`] describing its call, and schedules work on other accounts by
+//! returning a [`Promise`]. Promises are built from the same actions as the promises of contracts,
+//! so the runtime turns both into receipts the same way.
+
+use alloc::{format, string::String, vec, vec::Vec};
+use core::cell::RefCell;
+use codec::{Encode, Decode};
+use crate::abi;
+use crate::contract::{self, Gas, PromiseAction};
+use crate::state::{AccountId, Balance};
+
+/// The call a cross-chain method runs in.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Env {
+	/// The account the method is called on.
+	pub current_account: AccountId,
+	/// The account which signed the originating transaction.
+	pub signer: AccountId,
+	/// The account which caused this call, either the signer or a contract.
+	pub predecessor: AccountId,
+	/// Gas attached to the call.
+	pub prepaid_gas: Gas,
+	/// Log lines emitted by the call.
+	pub logs: RefCell<Vec<Vec<u8>>>,
+}
+
+impl Env {
+	/// Emit a log line.
+	pub fn log(&self, line: &[u8]) {
+		self.logs.borrow_mut().push(line.to_vec());
+	}
+}
+
+/// Actions scheduled by a cross-chain method, resolving to the result of the last one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Promise {
+	/// The promises in the order they were created, referring to earlier ones by index.
+	pub promises: Vec<contract::Promise>,
+}
+
+impl Promise {
+	/// Transfer `amount` to `receiver`.
+	pub fn transfer(receiver: AccountId, amount: Balance) -> Self {
+		Promise::action(receiver, PromiseAction::Transfer { amount })
+	}
+
+	/// Call `method` of `receiver` with the SCALE encoded tuple of its arguments, attaching
+	/// `deposit` and `gas`.
+	pub fn function_call(
+		receiver: AccountId,
+		method: &str,
+		args: impl Encode,
+		deposit: Balance,
+		gas: Gas,
+	) -> Self {
+		Promise::action(receiver, PromiseAction::FunctionCall {
+			method: method.into(),
+			args: args.encode(),
+			deposit,
+			gas,
+		})
+	}
+
+	fn action(receiver: AccountId, action: PromiseAction) -> Self {
+		Promise {
+			promises: vec![contract::Promise::Action { receiver, action, after: Vec::new() }],
+		}
+	}
+
+	/// A promise resolved once both `self` and `other` are.
+	pub fn and(mut self, other: Promise) -> Self {
+		let first = self.last();
+		let second = self.append(other, None);
+		self.promises.push(contract::Promise::Joint(vec![first, second]));
+		self
+	}
+
+	/// Run `next` once `self` is resolved, with its result.
+	pub fn then(mut self, next: Promise) -> Self {
+		let after = self.last();
+		self.append(next, Some(after));
+		self
+	}
+
+	/// Index of the promise this promise resolves to.
+	fn last(&self) -> u32 {
+		self.promises.len() as u32 - 1
+	}
+
+	/// Append the promises of `other`, running those which wait for nothing after the promise
+	/// `after`. Returns the new index of the last promise of `other`.
+	fn append(&mut self, other: Promise, after: Option<u32>) -> u32 {
+		let offset = self.promises.len() as u32;
+		for promise in other.promises {
+			self.promises.push(match promise {
+				contract::Promise::Action { receiver, action, after: waits_for } => {
+					let waits_for = match after {
+						Some(after) if waits_for.is_empty() => vec![after],
+						_ => waits_for.into_iter().map(|p| p + offset).collect(),
+					};
+					contract::Promise::Action { receiver, action, after: waits_for }
+				},
+				contract::Promise::Joint(promises) =>
+					contract::Promise::Joint(promises.into_iter().map(|p| p + offset).collect()),
+			});
+		}
+		self.last()
+	}
+}
+
+/// Either a value, or a promise resolving to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromiseOrValue<T> {
+	Value(T),
+	Promise(Promise),
+}
+
+impl<T> From<Promise> for PromiseOrValue<T> {
+	fn from(promise: Promise) -> Self {
+		PromiseOrValue::Promise(promise)
+	}
+}
+
+/// Something that can sort data across cross-chain calls.
+pub trait BuildCrossChain {
+	/// Sort `arr`, splitting the work across calls.
+	fn build_sort(&self, arr: Vec<u8>) -> PromiseOrValue<Vec<u8>>;
+
+	/// Merge the sorted results of two calls of `build_sort`.
+	fn build(&self, data0: Vec<u8>, data1: Vec<u8>) -> Vec<u8>;
+}
+
+/// The status message contract, called by `CrossChain`.
+pub trait ChainStatusMessage {
+	fn set_status(&mut self, message: String);
+	fn get_status(&self, account_id: AccountId) -> Option<String>;
+}
+
+/// This is our custom type, to be stored on-chain:
+#[derive(Default, Clone, Encode, Decode)]
+pub struct CrossChain {
+	/// The call the methods run in, which is not stored.
+	#[codec(skip)]
+	env: Env,
+}
+
+impl CrossChain {
+	/// `CrossChain` running its methods in `env`.
+	pub fn with_env(env: Env) -> Self {
+		CrossChain { env }
+	}
+
+	/// The call the methods run in.
+	pub fn env(&self) -> &Env {
+		&self.env
+	}
+}
+
+#[abi::abi]
+impl CrossChain {
+	/// Fund the account `id` with `amount`, then initialize the status message contract
+	/// deployed to it.
+	#[call]
+	pub fn deploy_status_message(&self, id: AccountId, amount: u64) -> Promise {
+		Promise::transfer(id, amount as Balance)
+			.then(Promise::function_call(id, "new", (), 0, self.env.prepaid_gas / 2))
+	}
+
+	#[result_encode]
+	pub fn build_sort(&self, arr: Vec<u8>) -> PromiseOrValue<Vec<u8>> {
+		if arr.len() <= 1 {
+			return PromiseOrValue::Value(arr);
+		}
+		let pivot = arr.len() / 2;
+		let arr0 = arr[..pivot].to_vec();
+		let arr1 = arr[pivot..].to_vec();
+		let gas = self.env.prepaid_gas / 3;
+		let account_id = self.env.current_account;
+
+		Promise::function_call(account_id, "build_sort", (arr0,), 0, gas)
+			.and(Promise::function_call(account_id, "build_sort", (arr1,), 0, gas))
+			.then(Promise::function_call(account_id, "build", (), 0, gas))
+			.into()
+	}
+
+	fn internal_build(&self, arr0: Vec<u8>, arr1: Vec<u8>) -> Vec<u8> {
+		let mut i = 0usize;
+		let mut j = 0usize;
+		let mut result = vec![];
+		loop {
+			if i == arr0.len() {
+				result.extend(&arr1[j..]);
+				break;
+			}
+			if j == arr1.len() {
+				result.extend(&arr0[i..]);
+				break;
+			}
+			if arr0[i] < arr1[j] {
+				result.push(arr0[i]);
+				i += 1;
+			} else {
+				result.push(arr1[j]);
+				j += 1;
+			}
+		}
+		result
+	}
+
+	#[result_encode]
+	pub fn build(
+		&self,
+		#[callback]
+		#[encode]
+		data0: Vec<u8>,
+		#[callback]
+		#[encode]
+		data1: Vec<u8>,
+	) -> Vec<u8> {
+		self.env.log(format!("Received {:?} and {:?}", data0, data1).as_bytes());
+		assert_eq!(self.env.current_account, self.env.predecessor);
+		let result = self.internal_build(data0, data1);
+		self.env.log(format!("Built {:?}", result).as_bytes());
+		result
+	}
+
+	pub fn simple_call(&mut self, account_id: AccountId, message: String) -> Promise {
+		Promise::function_call(account_id, "set_status", (message,), 0, self.env.prepaid_gas)
+	}
+
+	pub fn complex_call(&mut self, account_id: AccountId, message: String) -> Promise {
+		let gas = self.env.prepaid_gas / 2;
+		Promise::function_call(account_id, "set_status", (message,), 0, gas).then(
+			Promise::function_call(account_id, "get_status", (self.env.signer,), 0, gas),
+		)
+	}
+
+	pub fn transfer_money(&mut self, account_id: AccountId, amount: u64) -> Promise {
+		Promise::transfer(account_id, amount as Balance)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::boxed::Box;
+	use crate::abi::{Layout, MethodKind};
+
+	fn cross_chain(gas: Gas) -> CrossChain {
+		CrossChain::with_env(Env {
+			current_account: [1; 32],
+			signer: [2; 32],
+			predecessor: [1; 32],
+			prepaid_gas: gas,
+			..Default::default()
+		})
+	}
+
+	fn call(method: &str, args: impl Encode, gas: Gas, after: Vec<u32>) -> contract::Promise {
+		contract::Promise::Action {
+			receiver: [1; 32],
+			action: PromiseAction::FunctionCall {
+				method: method.into(),
+				args: args.encode(),
+				deposit: 0,
+				gas,
+			},
+			after,
+		}
+	}
+
+	#[test]
+	fn sorting_is_split_across_calls() {
+		let cross_chain = cross_chain(300);
+		assert_eq!(cross_chain.build_sort(vec![3]), PromiseOrValue::Value(vec![3]));
+
+		let promise = match cross_chain.build_sort(vec![3, 1, 2]) {
+			PromiseOrValue::Promise(promise) => promise,
+			PromiseOrValue::Value(value) => panic!("sorted {:?} in one call", value),
+		};
+		assert_eq!(promise.promises, vec![
+			call("build_sort", (vec![3u8],), 100, vec![]),
+			call("build_sort", (vec![1u8, 2],), 100, vec![]),
+			contract::Promise::Joint(vec![0, 1]),
+			call("build", (), 100, vec![2]),
+		]);
+	}
+
+	#[test]
+	fn sorted_data_is_merged() {
+		let cross_chain = cross_chain(0);
+		assert_eq!(cross_chain.build(vec![1, 4, 5], vec![2, 3, 6]), vec![1, 2, 3, 4, 5, 6]);
+		assert_eq!(cross_chain.env().logs.borrow().len(), 2);
+	}
+
+	#[test]
+	fn chained_promises_refer_to_their_own_promises() {
+		let first = Promise::transfer([1; 32], 1).then(Promise::transfer([2; 32], 2));
+		let second = Promise::transfer([3; 32], 3).and(Promise::transfer([4; 32], 4));
+		let promises = first.and(second).promises;
+
+		let after: Vec<_> = promises.iter().map(|promise| match promise {
+			contract::Promise::Action { after, .. } => after.clone(),
+			contract::Promise::Joint(promises) => promises.clone(),
+		}).collect();
+		assert_eq!(after, vec![vec![], vec![0], vec![], vec![], vec![2, 3], vec![1, 4]]);
+	}
+
+	#[test]
+	fn methods_are_described_by_the_abi() {
+		let abi = CrossChain::abi();
+		let kinds: Vec<_> = abi.methods.iter().map(|method| (method.name, method.kind)).collect();
+		assert_eq!(kinds, vec![
+			("deploy_status_message", MethodKind::Call),
+			("build_sort", MethodKind::Call),
+			("build", MethodKind::Call),
+			("simple_call", MethodKind::Call),
+			("complex_call", MethodKind::Call),
+			("transfer_money", MethodKind::Call),
+		]);
+
+		let build = abi.method("build").unwrap();
+		assert!(build.args.iter().all(|arg| arg.callback));
+		assert_eq!(build.returns, Some(Layout::Sequence(Box::new(Layout::U8))));
+		assert_eq!(abi.method("transfer_money").unwrap().returns, Some(Layout::Promise));
+	}
+}
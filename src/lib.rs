@@ -4,8 +4,32 @@
 
 #![cfg_attr(not(feature = "std"), feature(core_intrinsics, lang_items, core_panic_info, alloc_error_handler))]
 
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+// Lets code generated by `powerplay-macros` refer to this crate as `powerplay`.
+extern crate self as powerplay;
+
+use alloc::vec::Vec;
 use codec::{Encode, Decode};
 
+pub mod abi;
+pub mod contract;
+pub mod cross_chain;
+mod state;
+mod transaction;
+#[cfg(feature = "std")]
+pub mod view;
+
+pub use cross_chain::CrossChain;
+pub use state::{Account, AccountId, Balance, ChainId, State, Storage};
+pub use parachain::primitives::Id as ParaId;
+pub use transaction::{
+	Action, Era, SignedTransaction, Transaction, TransactionError, GAS_PRICE, MAX_BLOCK_GAS,
+	MAX_ERA_PERIOD, MAX_RECEIPTS_PER_BLOCK,
+};
+
 #[cfg(not(feature = "std"))]
 mod wasm_validation;
 
@@ -34,118 +58,68 @@ impl HeadData {
 /// Block data for this parachain.
 #[derive(Default, Clone, Encode, Decode)]
 pub struct BlockData {
-	pub state: [u8; 32],
+	/// The state the block is built on. Its hash must match the parent's `post_state`.
+	pub state: State,
 	pub data: CrossChain,
+	/// Transactions to apply, in order.
+	pub transactions: Vec<SignedTransaction>,
 }
 
-/// This is our custom type, to be stored on-chain:
-#[derive(Default, Clone, Encode, Decode)]
-pub struct CrossChain {}
-
-// Something that can run a cross-chain function:
-pub trait BuildCrossChain {
-	/// The crosschain context produced by the `build` function.
-	type CrossChain: self::CrossChain;
-	
-	/// Build the `CrossChain`
-    fn build_sort(&self, arr: Vec<u8>) -> PromiseOrValue<Vec<u8>>;
-    fn build(
-        &self,
-        #[callback]
-        #[encode]
-        data0: Vec<u8>,
-        #[callback]
-        #[encode]
-        data1: Vec<u8>,
-    ) -> CrossChain Vec<u8>;
+/// Error which occurs when a block can't be executed on top of its parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecutionError {
+	/// The state in the block data doesn't match the parent's `post_state`.
+	StateMismatch,
+	/// The transaction at `index` can't be included in the block.
+	InvalidTransaction {
+		index: usize,
+		error: TransactionError,
+	},
 }
 
-pub trait ChainStatusMessage {
-	fn set_status(&mut self, message: String);
-	fn get_status(&self, id: String) -> Option<String>;
+/// Execute a block on top of its parent, returning the new head and the post-state.
+pub fn apply(
+	parent_hash: [u8; 32],
+	parent_head: HeadData,
+	block_data: &BlockData,
+) -> Result<(HeadData, State), ExecutionError> {
+	debug_assert_eq!(parent_hash, parent_head.hash());
+
+	if block_data.state.hash() != parent_head.post_state {
+		return Err(ExecutionError::StateMismatch);
+	}
+
+	let number = parent_head.number + 1;
+	let mut state = block_data.state.clone();
+
+	// The genesis state can't contain the hash of its own head, so it is recorded by the first
+	// block, before any transaction is checked against it.
+	if parent_head.number == 0 {
+		state.chain.genesis_hash = parent_hash;
+	}
+
+	// Remember recent head hashes, so mortal transactions can refer to them.
+	state.block_hashes.insert(parent_head.number, parent_hash);
+	let oldest = number.saturating_sub(MAX_ERA_PERIOD);
+	state.block_hashes = state.block_hashes.split_off(&oldest);
+
+	transaction::Executive::new(&mut state, number)
+		.apply(&block_data.transactions)
+		.map_err(|(index, error)| ExecutionError::InvalidTransaction { index, error })?;
+
+	let head = HeadData {
+		number,
+		parent_hash,
+		post_state: state.hash(),
+	};
+	Ok((head, state))
 }
 
-impl CrossChain {
-    pub fn deploy_status_message(&self, id: String, amount: u64) {
-        Promise::new(account_id)
-            .create_account()
-            .transfer(amount as u128)
-            .add_full_access_key(env::signer_account_pk())
-            .deploy_crosschain();
-    }
-
-    #[result_encode]
-    pub fn build_sort(&self, arr: Vec<u8>) -> PromiseOrValue<Vec<u8>> {
-        if arr.len() <= 1 {
-            return PromiseOrValue::Value(arr);
-        }
-        let pivot = arr.len() / 2;
-        let arr0 = arr[..pivot].to_vec();
-        let arr1 = arr[pivot..].to_vec();
-        let prepaid_gas = env::prepaid_gas();
-        let account_id = env::current_account_id();
-
-        ext::build_sort(arr0, &account_id, 0)
-            .and(ext::build_sort(arr1, &account_id, 0))
-            .then(ext::build(&account_id, 0))
-            .into()
-    }
-
-    fn internal_build(&self, arr0: Vec<u8>, arr1: Vec<u8>) -> Vec<u8> {
-        let mut i = 0usize;
-        let mut j = 0usize;
-        let mut result = vec![];
-        loop {
-            if i == arr0.len() {
-                result.extend(&arr1[j..]);
-                break;
-            }
-            if j == arr1.len() {
-                result.extend(&arr0[i..]);
-                break;
-            }
-            if arr0[i] < arr1[j] {
-                result.push(arr0[i]);
-                i += 1;
-            } else {
-                result.push(arr1[j]);
-                j += 1;
-            }
-        }
-        result
-    }
-
-    #[result_encode]
-    pub fn build(
-        &self,
-        #[callback]
-        #[encode]
-        data0: Vec<u8>,
-        #[callback]
-        #[encode]
-        data1: Vec<u8>,
-    ) -> Vec<u8> {
-        env::log(format!("Received {:?} and {:?}", data0, data1).as_bytes());
-        assert_eq!(env::current_account_id(), env::predecessor_account_id());
-        let result = self.internal_merge(data0, data1);
-        env::log(format!("Built {:?}", result).as_bytes());
-        result
-    }
-
-    pub fn simple_call(&mut self, account_id: String, message: String) {
-        ext_status_message::set_status(message, &account_id, 0);
-    }
-    pub fn complex_call(&mut self, account_id: String, message: String) -> Promise {
-        ext_status_message::set_status(message, &account_id, 0).then(
-            ext_status_message::get_status(
-                env::signer_account_id(),
-                &account_id,
-                0,
-            ),
-        )
-    }
-
-    pub fn transfer_money(&mut self, account_id: String, amount: u64) {
-        Promise::new(account_id).transfer(amount as u128);
-    }
+/// Execute a block on top of its parent, returning the new head.
+pub fn execute(
+	parent_hash: [u8; 32],
+	parent_head: HeadData,
+	block_data: &BlockData,
+) -> Result<HeadData, ExecutionError> {
+	apply(parent_hash, parent_head, block_data).map(|(head, _)| head)
 }
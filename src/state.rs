@@ -0,0 +1,125 @@
+//! Account state of the powerplay parachain.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use codec::{Encode, Decode};
+use parachain::primitives::Id as ParaId;
+
+/// Identifier of an account.
+pub type AccountId = [u8; 32];
+
+/// Balance of an account.
+pub type Balance = u128;
+
+/// Contract storage, mapping keys to values.
+pub type Storage = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// A single account.
+#[derive(Default, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Account {
+	/// The nonce the next transaction signed by this account must have.
+	pub nonce: u64,
+	/// Free balance of the account.
+	pub balance: Balance,
+	/// Contract code deployed to the account, if any.
+	pub code: Option<Vec<u8>>,
+	/// Storage owned by the contract deployed to the account.
+	pub storage: Storage,
+}
+
+/// Identifies a powerplay chain. Transactions are signed for a specific chain.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct ChainId {
+	/// Hash of the genesis head. All zeros in the genesis state, which can't commit to its own
+	/// head, and set when the first block is applied.
+	pub genesis_hash: [u8; 32],
+	/// The id of the parachain on the relay chain.
+	pub para_id: ParaId,
+}
+
+/// The full state of the parachain.
+#[derive(Default, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct State {
+	/// The chain this state belongs to.
+	pub chain: ChainId,
+	/// All accounts, by id.
+	pub accounts: BTreeMap<AccountId, Account>,
+	/// Hashes of the most recent heads, by number, used to check transaction mortality.
+	pub block_hashes: BTreeMap<u64, [u8; 32]>,
+}
+
+impl State {
+	/// An empty genesis state for the parachain `para_id`.
+	pub fn new(para_id: ParaId) -> Self {
+		State {
+			chain: ChainId { genesis_hash: [0; 32], para_id },
+			..Default::default()
+		}
+	}
+
+	/// The hash of the state, committed to by `HeadData::post_state`.
+	pub fn hash(&self) -> [u8; 32] {
+		tiny_keccak::keccak256(&self.encode())
+	}
+
+	/// Get an account, if it exists.
+	pub fn account(&self, id: &AccountId) -> Option<&Account> {
+		self.accounts.get(id)
+	}
+
+	/// Get an account for modification, creating an empty one if it does not exist.
+	pub fn account_mut(&mut self, id: &AccountId) -> &mut Account {
+		self.accounts.entry(*id).or_insert_with(Default::default)
+	}
+
+	/// Move `amount` from one account to another.
+	///
+	/// Returns `false` and leaves the state untouched if `from` can't afford it, or the balance
+	/// of `to` would overflow.
+	pub fn transfer(&mut self, from: &AccountId, to: &AccountId, amount: Balance) -> bool {
+		match self.account(from) {
+			Some(account) if account.balance >= amount => {},
+			_ => return amount == 0,
+		}
+		if from == to {
+			return true;
+		}
+		let balance = self.account(to).map_or(0, |account| account.balance);
+		let credited = match balance.checked_add(amount) {
+			Some(credited) => credited,
+			None => return false,
+		};
+
+		self.account_mut(from).balance -= amount;
+		self.account_mut(to).balance = credited;
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn transfers_which_would_overflow_fail() {
+		let mut state = State::new(ParaId::from(100));
+		state.account_mut(&[1; 32]).balance = 10;
+		state.account_mut(&[2; 32]).balance = Balance::max_value() - 5;
+
+		assert!(!state.transfer(&[1; 32], &[2; 32], 6));
+		assert_eq!(state.account(&[1; 32]).unwrap().balance, 10);
+		assert_eq!(state.account(&[2; 32]).unwrap().balance, Balance::max_value() - 5);
+
+		assert!(state.transfer(&[1; 32], &[2; 32], 5));
+		assert_eq!(state.account(&[1; 32]).unwrap().balance, 5);
+		assert_eq!(state.account(&[2; 32]).unwrap().balance, Balance::max_value());
+	}
+
+	#[test]
+	fn transfers_to_the_sender_keep_the_balance() {
+		let mut state = State::new(ParaId::from(100));
+		state.account_mut(&[1; 32]).balance = Balance::max_value();
+
+		assert!(state.transfer(&[1; 32], &[1; 32], Balance::max_value()));
+		assert_eq!(state.account(&[1; 32]).unwrap().balance, Balance::max_value());
+	}
+}
@@ -0,0 +1,618 @@
+//! Transactions and their application to the state.
+//!
+//! Transactions are signed by their signer, whose account id is an ed25519 public key, for a
+//! specific chain: the signature commits to the genesis hash and para id, so it is not valid on
+//! any other powerplay deployment. Each transaction carries the signer's next nonce, so it can
+//! only be applied once, and optionally an era limiting the blocks it can be included in. A block
+//! with an invalid transaction is invalid.
+//!
+//! Every transaction is turned into a receipt. Contract calls can create promises, which become
+//! further receipts executed later in the same block. A receipt which fails has its effects
+//! reverted, but does not make the block invalid.
+//!
+//! The gas of a contract call is paid for by the signer at `GAS_PRICE` when the transaction is
+//! applied, and the gas the call and the promises it created didn't use is refunded. The gas of
+//! all transactions in a block is limited to `MAX_BLOCK_GAS`.
+
+use alloc::{collections::{BTreeMap, VecDeque}, string::String, vec, vec::Vec};
+use codec::{Encode, Decode};
+use sp_core::ed25519;
+use crate::contract::{self, CallContext, Gas, Promise, PromiseAction};
+use crate::state::{AccountId, Balance, ChainId, State};
+
+/// Maximum number of receipts executed in one block, including the transactions themselves.
+pub const MAX_RECEIPTS_PER_BLOCK: usize = 1024;
+
+/// Maximum gas of all transactions in one block.
+pub const MAX_BLOCK_GAS: Gas = 1_000_000_000;
+
+/// Balance paid for every unit of gas.
+pub const GAS_PRICE: Balance = 1;
+
+/// Maximum number of blocks a mortal transaction can be valid for.
+///
+/// The state keeps the hashes of this many recent heads.
+pub const MAX_ERA_PERIOD: u64 = 256;
+
+/// An action carried out by a transaction.
+#[derive(Clone, PartialEq, Eq, Encode, Decode)]
+pub enum Action {
+	/// Transfer balance from the signer to the receiver.
+	Transfer {
+		amount: Balance,
+	},
+	/// Deploy contract code to the receiver, which must be the signer.
+	DeployContract {
+		code: Vec<u8>,
+	},
+	/// Call a method of the contract deployed to the receiver.
+	FunctionCall {
+		method: String,
+		args: Vec<u8>,
+		deposit: Balance,
+		gas: Gas,
+	},
+}
+
+/// The blocks a transaction can be included in.
+#[derive(Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum Era {
+	/// The transaction is valid in any block.
+	Immortal,
+	/// The transaction is valid in the `period` blocks following block `birth`, on the chain in
+	/// which the head of block `birth` has hash `birth_hash`.
+	Mortal {
+		birth: u64,
+		birth_hash: [u8; 32],
+		period: u64,
+	},
+}
+
+impl Default for Era {
+	fn default() -> Self {
+		Era::Immortal
+	}
+}
+
+/// A transaction.
+#[derive(Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Transaction {
+	/// The account which sent the transaction.
+	pub signer: AccountId,
+	/// The nonce of the signer's account when the transaction is applied.
+	pub nonce: u64,
+	/// The blocks the transaction is valid in.
+	pub era: Era,
+	/// The account the action is carried out on.
+	pub receiver: AccountId,
+	/// What to do.
+	pub action: Action,
+}
+
+impl Transaction {
+	/// The data the signer signs to send the transaction on `chain`.
+	pub fn signing_payload(&self, chain: &ChainId) -> Vec<u8> {
+		(chain, self).encode()
+	}
+
+	/// Sign the transaction for `chain` with `pair`, whose public key must be the signer.
+	#[cfg(feature = "std")]
+	pub fn sign(self, chain: &ChainId, pair: &ed25519::Pair) -> SignedTransaction {
+		use sp_core::Pair as _;
+
+		let signature = pair.sign(&self.signing_payload(chain));
+		SignedTransaction { transaction: self, signature }
+	}
+}
+
+/// A transaction with the signature of its signer, as included in a block.
+#[derive(Clone, PartialEq, Eq, Encode, Decode)]
+pub struct SignedTransaction {
+	/// The transaction.
+	pub transaction: Transaction,
+	/// Signature of the transaction's signing payload for this chain by the signer.
+	pub signature: ed25519::Signature,
+}
+
+/// Why a transaction can't be included in a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+	/// The signature is not valid for the signer, or was made for another chain.
+	BadSignature,
+	/// The nonce is not the next nonce of the signer.
+	BadNonce {
+		expected: u64,
+		found: u64,
+	},
+	/// The block is outside of the transaction's era.
+	Expired,
+	/// The era's birth block is not part of this chain.
+	UnknownBirth,
+	/// The gas of the transactions in the block exceeds `MAX_BLOCK_GAS`.
+	BlockGasLimit,
+	/// The signer can't pay for the gas of the transaction.
+	CantPayGas,
+}
+
+impl SignedTransaction {
+	/// Check the transaction for inclusion in block `block_number` on top of `state`, and bump
+	/// the signer's nonce.
+	fn check(&self, state: &mut State, block_number: u64) -> Result<(), TransactionError> {
+		let tx = &self.transaction;
+
+		let signer = ed25519::Public::from_raw(tx.signer);
+		let payload = tx.signing_payload(&state.chain);
+		if !runtime_io::crypto::ed25519_verify(&self.signature, &payload, &signer) {
+			return Err(TransactionError::BadSignature);
+		}
+
+		if let Era::Mortal { birth, birth_hash, period } = tx.era {
+			let period = period.min(MAX_ERA_PERIOD);
+			if block_number <= birth || block_number > birth.saturating_add(period) {
+				return Err(TransactionError::Expired);
+			}
+
+			if state.block_hashes.get(&birth) != Some(&birth_hash) {
+				return Err(TransactionError::UnknownBirth);
+			}
+		}
+
+		let account = state.account_mut(&tx.signer);
+		if account.nonce != tx.nonce {
+			return Err(TransactionError::BadNonce { expected: account.nonce, found: tx.nonce });
+		}
+		account.nonce += 1;
+
+		Ok(())
+	}
+}
+
+impl From<PromiseAction> for Action {
+	fn from(action: PromiseAction) -> Self {
+		match action {
+			PromiseAction::FunctionCall { method, args, deposit, gas } =>
+				Action::FunctionCall { method, args, deposit, gas },
+			PromiseAction::Transfer { amount } => Action::Transfer { amount },
+		}
+	}
+}
+
+type ReceiptId = u64;
+
+struct Receipt {
+	id: ReceiptId,
+	signer: AccountId,
+	predecessor: AccountId,
+	receiver: AccountId,
+	action: Action,
+	/// Receipts whose results are passed to this one, in order.
+	after: Vec<ReceiptId>,
+}
+
+enum Outcome {
+	/// The receipt succeeded, returning the given data.
+	Value(Vec<u8>),
+	/// The receipt failed.
+	Failed,
+	/// The receipt succeeded, its result is the result of another receipt.
+	Forward(ReceiptId),
+}
+
+/// Applies transactions and the receipts they create to a state.
+pub(crate) struct Executive<'a> {
+	state: &'a mut State,
+	block_number: u64,
+	queue: VecDeque<Receipt>,
+	outcomes: BTreeMap<ReceiptId, Outcome>,
+	next_id: ReceiptId,
+	/// Gas bought by the transactions applied so far.
+	block_gas: Gas,
+}
+
+impl<'a> Executive<'a> {
+	/// Create an executive building block number `block_number` on top of `state`.
+	pub(crate) fn new(state: &'a mut State, block_number: u64) -> Self {
+		Executive {
+			state,
+			block_number,
+			queue: VecDeque::new(),
+			outcomes: BTreeMap::new(),
+			next_id: 0,
+			block_gas: 0,
+		}
+	}
+
+	/// Apply all `transactions`, and then all receipts they create.
+	///
+	/// Fails with the index of the first invalid transaction.
+	pub(crate) fn apply(
+		mut self,
+		transactions: &[SignedTransaction],
+	) -> Result<(), (usize, TransactionError)> {
+		for (index, signed) in transactions.iter().enumerate() {
+			signed.check(self.state, self.block_number).map_err(|err| (index, err))?;
+
+			let tx = &signed.transaction;
+			if let Action::FunctionCall { gas, .. } = tx.action {
+				self.buy_gas(&tx.signer, gas).map_err(|err| (index, err))?;
+			}
+			self.push(tx.signer, tx.signer, tx.receiver, tx.action.clone(), Vec::new());
+		}
+
+		// Receipts waiting on others are moved to the back of the queue. Every receipt only waits on
+		// receipts created before it, or forwarded to by those, so there is always progress.
+		let mut stalled = 0;
+		while let Some(receipt) = self.queue.pop_front() {
+			let results = receipt.after.iter()
+				.map(|id| self.result(*id))
+				.collect::<Option<Vec<_>>>();
+
+			match results {
+				Some(results) => {
+					stalled = 0;
+					let outcome = self.execute(&receipt, &results);
+					self.outcomes.insert(receipt.id, outcome);
+				},
+				None if stalled > self.queue.len() => {
+					self.refund_gas(&receipt, 0);
+					self.outcomes.insert(receipt.id, Outcome::Failed);
+				},
+				None => {
+					stalled += 1;
+					self.queue.push_back(receipt);
+				},
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Make `signer` pay for `gas` bought by a transaction.
+	fn buy_gas(&mut self, signer: &AccountId, gas: Gas) -> Result<(), TransactionError> {
+		self.block_gas = self.block_gas.checked_add(gas)
+			.filter(|block_gas| *block_gas <= MAX_BLOCK_GAS)
+			.ok_or(TransactionError::BlockGasLimit)?;
+
+		let cost = Balance::from(gas).saturating_mul(GAS_PRICE);
+		let account = self.state.account_mut(signer);
+		account.balance = account.balance.checked_sub(cost).ok_or(TransactionError::CantPayGas)?;
+		Ok(())
+	}
+
+	/// Refund the gas of a function call receipt which only used `used` of it to its signer.
+	fn refund_gas(&mut self, receipt: &Receipt, used: Gas) {
+		if let Action::FunctionCall { gas, .. } = receipt.action {
+			let refund = Balance::from(gas.saturating_sub(used)).saturating_mul(GAS_PRICE);
+			let account = self.state.account_mut(&receipt.signer);
+			account.balance = account.balance.saturating_add(refund);
+		}
+	}
+
+	fn push(
+		&mut self,
+		signer: AccountId,
+		predecessor: AccountId,
+		receiver: AccountId,
+		action: Action,
+		after: Vec<ReceiptId>,
+	) -> ReceiptId {
+		let id = self.next_id;
+		self.next_id += 1;
+
+		let receipt = Receipt { id, signer, predecessor, receiver, action, after };
+		if id as usize >= MAX_RECEIPTS_PER_BLOCK {
+			self.refund_gas(&receipt, 0);
+			self.outcomes.insert(id, Outcome::Failed);
+		} else {
+			self.queue.push_back(receipt);
+		}
+
+		id
+	}
+
+	/// The result of a receipt: `None` if it is still pending, `Some(None)` if it failed.
+	fn result(&self, mut id: ReceiptId) -> Option<Option<Vec<u8>>> {
+		loop {
+			match self.outcomes.get(&id)? {
+				Outcome::Value(data) => return Some(Some(data.clone())),
+				Outcome::Failed => return Some(None),
+				Outcome::Forward(to) => id = *to,
+			}
+		}
+	}
+
+	fn execute(&mut self, receipt: &Receipt, results: &[Option<Vec<u8>>]) -> Outcome {
+		match receipt.action {
+			Action::Transfer { amount } => {
+				if self.state.transfer(&receipt.predecessor, &receipt.receiver, amount) {
+					Outcome::Value(Vec::new())
+				} else {
+					Outcome::Failed
+				}
+			},
+			Action::DeployContract { ref code } => {
+				if receipt.predecessor != receipt.receiver || contract::prepare(code).is_err() {
+					return Outcome::Failed;
+				}
+
+				self.state.account_mut(&receipt.receiver).code = Some(code.clone());
+				Outcome::Value(Vec::new())
+			},
+			Action::FunctionCall { ref method, ref args, deposit, gas } =>
+				self.call(receipt, method, args, deposit, gas, results),
+		}
+	}
+
+	fn call(
+		&mut self,
+		receipt: &Receipt,
+		method: &str,
+		args: &[u8],
+		deposit: Balance,
+		gas: Gas,
+		results: &[Option<Vec<u8>>],
+	) -> Outcome {
+		let code = match self.state.account(&receipt.receiver).and_then(|a| a.code.clone()) {
+			Some(code) => code,
+			None => {
+				self.refund_gas(receipt, 0);
+				return Outcome::Failed;
+			},
+		};
+
+		if !self.state.transfer(&receipt.predecessor, &receipt.receiver, deposit) {
+			self.refund_gas(receipt, 0);
+			return Outcome::Failed;
+		}
+
+		let account = self.state.account_mut(&receipt.receiver);
+		let context = CallContext {
+			current_account: receipt.receiver,
+			signer: receipt.signer,
+			predecessor: receipt.predecessor,
+			block_number: self.block_number,
+			balance: account.balance,
+			deposit,
+			gas_limit: gas,
+			promise_results: results,
+			read_only: false,
+		};
+
+		let mut storage = account.storage.clone();
+		let outcome = match contract::call(&code, method, args.to_vec(), &mut storage, &context) {
+			Ok(outcome) => outcome,
+			// The gas of a failed call is not refunded.
+			Err(_) => {
+				self.state.transfer(&receipt.receiver, &receipt.predecessor, deposit);
+				return Outcome::Failed;
+			},
+		};
+		account.storage = storage;
+		self.refund_gas(receipt, outcome.gas_used);
+
+		// Turn the promises of the call into receipts. Promise indices only refer to earlier
+		// promises, so they can be mapped to receipt ids in order.
+		let mut ids: Vec<Vec<ReceiptId>> = Vec::with_capacity(outcome.promises.len());
+		for promise in outcome.promises {
+			let receipt_ids = match promise {
+				Promise::Action { receiver, action, after } => {
+					let after = after.iter().flat_map(|p| ids[*p as usize].iter().cloned()).collect();
+					let id = self.push(receipt.signer, receipt.receiver, receiver, action.into(), after);
+					vec![id]
+				},
+				Promise::Joint(promises) =>
+					promises.iter().flat_map(|p| ids[*p as usize].iter().cloned()).collect(),
+			};
+			ids.push(receipt_ids);
+		}
+
+		// Only non-joint promises can be returned, which map to exactly one receipt.
+		match outcome.returned_promise {
+			Some(promise) => Outcome::Forward(ids[promise as usize][0]),
+			None => Outcome::Value(outcome.return_data),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::Pair;
+
+	const CONTRACT: &str = r#"
+		(module
+			(import "env" "memory" (memory 1 1))
+			(import "env" "current_account_id" (func $current_account_id (param i32)))
+			(import "env" "return_value" (func $return_value (param i32 i32)))
+			(import "env" "scratch_len" (func $scratch_len (result i32)))
+			(import "env" "scratch_read" (func $scratch_read (param i32)))
+			(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+			(import "env" "promise_create"
+				(func $promise_create (param i32 i32 i32 i32 i32 i32 i64) (result i32)))
+			(import "env" "promise_then"
+				(func $promise_then (param i32 i32 i32 i32 i32 i32 i32 i64) (result i32)))
+			(import "env" "promise_result" (func $promise_result (param i32) (result i32)))
+
+			;; Method names, a return value and a storage key. The zeros at 32 are a balance.
+			(data (i32.const 0) "answer")
+			(data (i32.const 8) "record")
+			(data (i32.const 16) "42")
+			(data (i32.const 24) "key")
+
+			(func (export "answer")
+				(call $return_value (i32.const 16) (i32.const 2)))
+
+			;; Write to storage, then trap.
+			(func (export "fail")
+				(call $storage_write (i32.const 24) (i32.const 3) (i32.const 16) (i32.const 2))
+				unreachable)
+
+			;; Call "answer", then pass its result to "record".
+			(func (export "chain")
+				(call $current_account_id (i32.const 64))
+				(drop (call $promise_then
+					(call $promise_create
+						(i32.const 64) (i32.const 0) (i32.const 6) (i32.const 0) (i32.const 0)
+						(i32.const 32) (i64.const 100000))
+					(i32.const 64) (i32.const 8) (i32.const 6) (i32.const 0) (i32.const 0)
+					(i32.const 32) (i64.const 100000))))
+
+			;; Store the result of the first promise under "key".
+			(func (export "record")
+				(if (call $promise_result (i32.const 0))
+					(then
+						(call $scratch_read (i32.const 128))
+						(call $storage_write
+							(i32.const 24) (i32.const 3) (i32.const 128) (call $scratch_len)))))
+		)
+	"#;
+
+	const BALANCE: Balance = 1_000_000_000_000;
+
+	fn pair() -> ed25519::Pair {
+		ed25519::Pair::from_seed(&[1; 32])
+	}
+
+	/// A state in which the signer of `pair` has the test contract deployed.
+	fn initial_state() -> State {
+		let mut state = State::default();
+		let account = state.account_mut(&pair().public().0);
+		account.balance = BALANCE;
+		account.code = Some(wat::parse_str(CONTRACT).unwrap());
+		state
+	}
+
+	fn transaction(nonce: u64, era: Era, action: Action) -> Transaction {
+		let signer = pair().public().0;
+		Transaction { signer, nonce, era, receiver: signer, action }
+	}
+
+	fn call(nonce: u64, method: &str, deposit: Balance, gas: Gas) -> Transaction {
+		let action = Action::FunctionCall { method: method.into(), args: Vec::new(), deposit, gas };
+		transaction(nonce, Era::Immortal, action)
+	}
+
+	fn apply(
+		state: &mut State,
+		block_number: u64,
+		transactions: Vec<Transaction>,
+	) -> Result<(), (usize, TransactionError)> {
+		let chain = state.chain;
+		let signed = transactions.into_iter()
+			.map(|tx| tx.sign(&chain, &pair()))
+			.collect::<Vec<_>>();
+		Executive::new(state, block_number).apply(&signed)
+	}
+
+	fn account(state: &State) -> &crate::state::Account {
+		state.account(&pair().public().0).unwrap()
+	}
+
+	#[test]
+	fn promise_results_are_passed_on() {
+		let mut state = initial_state();
+		apply(&mut state, 1, vec![call(0, "chain", 0, 1_000_000)]).unwrap();
+		assert_eq!(account(&state).storage.get(&b"key"[..]), Some(&b"42".to_vec()));
+	}
+
+	#[test]
+	fn failed_calls_are_reverted() {
+		let mut state = initial_state();
+		apply(&mut state, 1, vec![call(0, "fail", 10, 1_000_000)]).unwrap();
+		assert!(account(&state).storage.is_empty());
+		// The deposit is returned, but not the gas.
+		assert_eq!(account(&state).balance, BALANCE - 1_000_000 * GAS_PRICE);
+		assert_eq!(account(&state).nonce, 1);
+	}
+
+	#[test]
+	fn unused_gas_is_refunded() {
+		let mut state = initial_state();
+		apply(&mut state, 1, vec![call(0, "answer", 0, 1_000_000)]).unwrap();
+		let paid = BALANCE - account(&state).balance;
+		assert!(paid > 0 && paid < 1_000_000 * GAS_PRICE);
+	}
+
+	#[test]
+	fn gas_is_limited() {
+		let mut state = initial_state();
+		let calls = vec![
+			call(0, "answer", 0, MAX_BLOCK_GAS / 2),
+			call(1, "answer", 0, MAX_BLOCK_GAS),
+		];
+		assert_eq!(apply(&mut state, 1, calls), Err((1, TransactionError::BlockGasLimit)));
+
+		let mut state = initial_state();
+		let calls = vec![call(0, "answer", 0, u64::max_value())];
+		assert_eq!(apply(&mut state, 1, calls), Err((0, TransactionError::BlockGasLimit)));
+
+		let mut state = initial_state();
+		state.account_mut(&pair().public().0).balance = 1000;
+		let calls = vec![call(0, "answer", 0, 1001)];
+		assert_eq!(apply(&mut state, 1, calls), Err((0, TransactionError::CantPayGas)));
+	}
+
+	fn transfer(nonce: u64, era: Era) -> Transaction {
+		transaction(nonce, era, Action::Transfer { amount: 1 })
+	}
+
+	#[test]
+	fn nonces_prevent_replays() {
+		let mut state = initial_state();
+		apply(&mut state, 1, vec![transfer(0, Era::Immortal), transfer(1, Era::Immortal)]).unwrap();
+		assert_eq!(account(&state).nonce, 2);
+
+		let bad_nonce = |found| Err((0, TransactionError::BadNonce { expected: 2, found }));
+		assert_eq!(apply(&mut state, 2, vec![transfer(1, Era::Immortal)]), bad_nonce(1));
+		assert_eq!(apply(&mut state, 2, vec![transfer(3, Era::Immortal)]), bad_nonce(3));
+		// A transaction can't be included twice in the same block either.
+		let replay = vec![transfer(2, Era::Immortal), transfer(2, Era::Immortal)];
+		assert_eq!(
+			apply(&mut state, 2, replay),
+			Err((1, TransactionError::BadNonce { expected: 3, found: 2 })),
+		);
+	}
+
+	#[test]
+	fn mortal_transactions_are_only_valid_in_their_era() {
+		let mut state = initial_state();
+		state.block_hashes.insert(10, [10; 32]);
+		let era = |birth_hash, period| Era::Mortal { birth: 10, birth_hash, period };
+		let expired = Err((0, TransactionError::Expired));
+
+		// Before and after the era.
+		assert_eq!(apply(&mut state, 10, vec![transfer(0, era([10; 32], 4))]), expired);
+		assert_eq!(apply(&mut state, 15, vec![transfer(0, era([10; 32], 4))]), expired);
+		// The period is capped.
+		let long = transfer(0, era([10; 32], u64::max_value()));
+		assert_eq!(apply(&mut state, 10 + MAX_ERA_PERIOD + 1, vec![long]), expired);
+		// A birth block on another fork.
+		assert_eq!(
+			apply(&mut state, 12, vec![transfer(0, era([11; 32], 4))]),
+			Err((0, TransactionError::UnknownBirth)),
+		);
+
+		apply(&mut state, 11, vec![transfer(0, era([10; 32], 4))]).unwrap();
+		apply(&mut state, 14, vec![transfer(1, era([10; 32], 4))]).unwrap();
+	}
+
+	#[test]
+	fn signatures_are_bound_to_the_chain() {
+		let mut state = initial_state();
+		state.chain = ChainId { genesis_hash: [1; 32], para_id: 100.into() };
+
+		let other_chains = vec![
+			ChainId { genesis_hash: [2; 32], para_id: 100.into() },
+			ChainId { genesis_hash: [1; 32], para_id: 101.into() },
+		];
+		for chain in other_chains {
+			let signed = transfer(0, Era::Immortal).sign(&chain, &pair());
+			assert_eq!(
+				Executive::new(&mut state, 1).apply(&[signed]),
+				Err((0, TransactionError::BadSignature)),
+			);
+		}
+
+		apply(&mut state, 1, vec![transfer(0, Era::Immortal)]).unwrap();
+	}
+}
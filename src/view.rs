@@ -0,0 +1,115 @@
+//! Read-only calls into contracts, executed natively against the state of a given head.
+//!
+//! Views run outside of block production: they can't write storage or create promises, and
+//! their results are not part of any block. This makes them suitable for frontends reading the
+//! state of a contract, such as the status of a `ChainStatusMessage`.
+
+use std::collections::HashMap;
+use std::{fmt, vec::Vec};
+use codec::Decode;
+use crate::contract::{self, CallContext, ContractError, Gas};
+use crate::state::{AccountId, State};
+use crate::HeadData;
+
+/// Gas available to a view call.
+pub const VIEW_GAS_LIMIT: Gas = 300_000_000;
+
+/// Something that can provide the post-state of a head.
+pub trait StateSource {
+	/// The state `head` commits to, if known.
+	fn state(&self, head: &HeadData) -> Option<State>;
+}
+
+/// A single state, used for any head. Views fail if it doesn't match the head.
+impl StateSource for State {
+	fn state(&self, _: &HeadData) -> Option<State> {
+		Some(self.clone())
+	}
+}
+
+impl StateSource for HashMap<HeadData, State> {
+	fn state(&self, head: &HeadData) -> Option<State> {
+		self.get(head).cloned()
+	}
+}
+
+/// An error during a view call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViewError {
+	/// The state of the head is not known to the state source.
+	UnknownState,
+	/// The state provided for the head doesn't match its `post_state`.
+	StateMismatch,
+	/// There is no contract deployed to the account.
+	NoContract,
+	/// The contract call failed.
+	Contract(ContractError),
+	/// The returned data could not be decoded.
+	BadReturn,
+}
+
+impl fmt::Display for ViewError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ViewError::UnknownState => write!(f, "State of the head is unknown"),
+			ViewError::StateMismatch => write!(f, "State does not match the head"),
+			ViewError::NoContract => write!(f, "No contract deployed to the account"),
+			ViewError::Contract(err) => write!(f, "{}", err),
+			ViewError::BadReturn => write!(f, "View returned invalid data"),
+		}
+	}
+}
+
+impl std::error::Error for ViewError {}
+
+/// Call `method` of the contract deployed to `account` in read-only mode, on the state of `head`,
+/// returning the raw data it returned.
+///
+/// The signer and predecessor of a view call are the all-zero account, and no balance is
+/// attached.
+pub fn view_raw<S: StateSource + ?Sized>(
+	head: &HeadData,
+	source: &S,
+	account: &AccountId,
+	method: &str,
+	args: Vec<u8>,
+) -> Result<Vec<u8>, ViewError> {
+	let state = source.state(head).ok_or(ViewError::UnknownState)?;
+	if state.hash() != head.post_state {
+		return Err(ViewError::StateMismatch);
+	}
+
+	let account_data = state.account(account).ok_or(ViewError::NoContract)?;
+	let code = account_data.code.as_ref().ok_or(ViewError::NoContract)?;
+
+	let context = CallContext {
+		current_account: *account,
+		signer: AccountId::default(),
+		predecessor: AccountId::default(),
+		block_number: head.number,
+		balance: account_data.balance,
+		deposit: 0,
+		gas_limit: VIEW_GAS_LIMIT,
+		promise_results: &[],
+		read_only: true,
+	};
+
+	// Mutations trap in read-only mode, so this copy is never changed.
+	let mut storage = account_data.storage.clone();
+	contract::call(code, method, args, &mut storage, &context)
+		.map(|outcome| outcome.return_data)
+		.map_err(ViewError::Contract)
+}
+
+/// Call `method` of the contract deployed to `account` in read-only mode, on the state of `head`,
+/// and decode the returned data.
+pub fn view<R: Decode, S: StateSource + ?Sized>(
+	head: &HeadData,
+	source: &S,
+	account: &AccountId,
+	method: &str,
+	args: Vec<u8>,
+) -> Result<R, ViewError> {
+	let data = view_raw(head, source, account, method, args)?;
+	R::decode(&mut &data[..]).map_err(|_| ViewError::BadReturn)
+}